@@ -0,0 +1,18 @@
+use std::path::Path;
+
+fn main() {
+    // the player is embedded once it's been downloaded with src/web/vendor/update.sh,
+    // otherwise the page loads it from a cdn
+    let vendor = Path::new("src/web/vendor");
+    println!("cargo:rerun-if-changed={}", vendor.display());
+    println!("cargo:rustc-check-cfg=cfg(bundled_player)");
+
+    if vendor.join("shaka-player.compiled.js").exists() {
+        println!("cargo:rustc-cfg=bundled_player");
+    } else {
+        println!(
+            "cargo:warning=src/web/vendor/shaka-player.compiled.js is missing, viewers will load \
+             the player from jsDelivr, run src/web/vendor/update.sh to embed it"
+        );
+    }
+}
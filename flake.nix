@@ -29,6 +29,7 @@
                   lib.any (re: builtins.match re relPath != null) [
                     "/Cargo.toml"
                     "/Cargo.lock"
+                    "/build.rs"
                     "/src"
                     "/src/.*"
                   ]
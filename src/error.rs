@@ -13,5 +13,6 @@ error_chain! {
         Warp(warp::Error);
         Openssl(openssl::error::ErrorStack) #[cfg(feature = "tls")];
         Reqwest(reqwest::Error);
+        UrlParse(url::ParseError);
     }
 }
@@ -1,13 +1,37 @@
-use crate::{error::*, helpers::*};
+use crate::{error::*, helpers::*, store::MANIFEST_NAME};
 use log::*;
+use serde::Serialize;
 use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Write},
     net::SocketAddr,
     path::PathBuf,
-    process::{Child, Command},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+/// nanoseconds since the epoch, and never the same twice even if restarts come quickly
+/// or the clock goes back
+fn next_run_id() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let previous = LAST
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .expect("always Some");
+    now.max(previous + 1)
+}
+
 pub enum FfmpegInput {
     Rtmp(SocketAddr),
     File {
@@ -17,14 +41,151 @@ pub enum FfmpegInput {
 }
 
 pub enum FfmpegOutput {
-    // temp_dir_path
-    Dash(PathBuf),
+    Dash(DashOutput),
     Rtmp(SocketAddr),
 }
 
+pub enum DashOutput {
+    // output_dir_path
+    Dir(PathBuf),
+    // base url of our ingest server that ffmpeg PUTs files to
+    Http(String),
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum InputInfo {
+    Rtmp {
+        source: String,
+    },
+    File {
+        source: PathBuf,
+        seek_secs: Option<f32>,
+    },
+}
+
+/// what the encoder is currently doing, for status pages
+#[derive(Serialize, Clone)]
+pub struct EncoderInfo {
+    pub input: InputInfo,
+    pub output: String,
+    pub cpu_used: u8,
+    pub framerate: u8,
+    pub crf: u8,
+    pub video_bitrate: String,
+    pub video_resolution: String,
+    pub audio_bitrate: String,
+    pub audio_sample_rate: String,
+    pub subtitles_path: Option<PathBuf>,
+}
+
+/// from ffmpeg's -progress output
+#[derive(Serialize, Clone, Default)]
+pub struct EncoderStats {
+    pub frame: u64,
+    pub fps: f64,
+    pub bitrate_kbps: Option<f64>,
+    pub speed: Option<f64>,
+    pub dropped_frames: u64,
+    pub duplicated_frames: u64,
+}
+
+#[derive(Default)]
+struct EncoderStateInner {
+    info: Option<EncoderInfo>,
+    started: Option<Instant>,
+    stats: Option<EncoderStats>,
+    starts: u64,
+    ended: bool,
+    // the last lines ffmpeg printed, to explain why it failed
+    output_tail: VecDeque<String>,
+}
+
+/// shared with the web server so it can report on the encoder
+#[derive(Default)]
+pub struct EncoderState {
+    inner: Mutex<EncoderStateInner>,
+}
+
+impl EncoderState {
+    fn set_running(&self, info: EncoderInfo) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.info = Some(info);
+        inner.started = Some(Instant::now());
+        inner.stats = None;
+        inner.starts += 1;
+        inner.ended = false;
+        inner.output_tail.clear();
+    }
+
+    fn set_stopped(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.started = None;
+        inner.stats = None;
+    }
+
+    /// the input finished and ffmpeg exited cleanly, or it kept failing
+    pub fn set_ended(&self) {
+        self.inner.lock().unwrap().ended = true;
+    }
+
+    pub fn ended(&self) -> bool {
+        self.inner.lock().unwrap().ended
+    }
+
+    fn set_stats(&self, stats: EncoderStats) {
+        let mut inner = self.inner.lock().unwrap();
+        // the last block can arrive after ffmpeg exited
+        if inner.started.is_some() {
+            inner.stats = Some(stats);
+        }
+    }
+
+    fn push_output(&self, line: String) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.output_tail.len() == OUTPUT_TAIL_LINES {
+            inner.output_tail.pop_front();
+        }
+        inner.output_tail.push_back(line);
+    }
+
+    fn output_tail(&self) -> Vec<String> {
+        self.inner
+            .lock()
+            .unwrap()
+            .output_tail
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn stats(&self) -> Option<EncoderStats> {
+        self.inner.lock().unwrap().stats.clone()
+    }
+
+    /// how many times ffmpeg was started again after the first time
+    pub fn restarts(&self) -> u64 {
+        self.inner.lock().unwrap().starts.saturating_sub(1)
+    }
+
+    pub fn info(&self) -> Option<EncoderInfo> {
+        self.inner.lock().unwrap().info.clone()
+    }
+
+    /// None if ffmpeg isn't running
+    pub fn uptime(&self) -> Option<Duration> {
+        self.inner
+            .lock()
+            .unwrap()
+            .started
+            .map(|started| started.elapsed())
+    }
+}
+
 pub struct Ffmpeg {
     pub command: Option<Child>,
     pub verbose: bool,
+    pub state: Arc<EncoderState>,
 
     pub input: FfmpegInput,
     pub output: FfmpegOutput,
@@ -37,13 +198,57 @@ pub struct Ffmpeg {
     pub audio_bitrate: String,
     pub audio_sample_rate: String,
     pub subtitles_path: Option<PathBuf>,
+    pub utc_timing_url: String,
 }
 
+// how long ffmpeg gets to finish up after being asked to stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
+// lines of ffmpeg's output that are logged when it fails
+const OUTPUT_TAIL_LINES: usize = 10;
+
+const STREAM_PATH: &str = "stream";
+const STREAM_KEY: &str = "";
+
 impl Ffmpeg {
-    pub async fn run(&mut self) -> Result<()> {
-        let stream_path = "stream";
-        let stream_key = "";
+    pub fn info(&self) -> EncoderInfo {
+        let input = match &self.input {
+            FfmpegInput::Rtmp(addr) => InputInfo::Rtmp {
+                source: format!("rtmp://{}/{}/{}", addr, STREAM_PATH, STREAM_KEY),
+            },
+            FfmpegInput::File { path, seek } => InputInfo::File {
+                source: path.clone(),
+                seek_secs: seek.map(|seek| seek.as_secs_f32()),
+            },
+        };
+
+        let output = match &self.output {
+            FfmpegOutput::Dash(_) => "dash".to_string(),
+            FfmpegOutput::Rtmp(addr) => {
+                format!("rtmp://{}/{}/{}", addr, STREAM_PATH, STREAM_KEY)
+            }
+        };
+
+        EncoderInfo {
+            input,
+            output,
+            cpu_used: self.cpu_used,
+            framerate: self.framerate,
+            crf: self.crf,
+            video_bitrate: self.video_bitrate.clone(),
+            video_resolution: self.video_resolution.clone(),
+            audio_bitrate: self.audio_bitrate.clone(),
+            audio_sample_rate: self.audio_sample_rate.clone(),
+            subtitles_path: self.subtitles_path.clone(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.command.is_some()
+    }
 
+    /// spawns ffmpeg with the current settings
+    pub fn start(&mut self) -> Result<()> {
         let mut args: Vec<String> = Vec::new();
 
         macro_rules! append {
@@ -58,9 +263,12 @@ impl Ffmpeg {
             append!(args, "-hide_banner", "-loglevel", "warning", "-stats");
         }
 
+        // machine readable stats for our status and metrics
+        append!(args, "-progress", "pipe:1");
+
         match &self.input {
             FfmpegInput::Rtmp(addr) => {
-                let rtmp_addr = format!("rtmp://{}/{}/{}", addr, stream_path, stream_key);
+                let rtmp_addr = format!("rtmp://{}/{}/{}", addr, STREAM_PATH, STREAM_KEY);
                 append!(args, "-listen", "1", "-i", rtmp_addr);
             }
 
@@ -109,7 +317,9 @@ impl Ffmpeg {
         }
 
         match &self.output {
-            FfmpegOutput::Dash(_temp_dir_path) => {
+            FfmpegOutput::Dash(dash_output) => {
+                let run_id = next_run_id();
+
                 // conversion to vp9/vorbis
                 append!(
                     args,
@@ -174,9 +384,8 @@ impl Ffmpeg {
                     args,
                     "-f",
                     "dash",
-                    // remove chunk files at exit
-                    "-remove_at_exit",
-                    "1",
+                    // no -remove_at_exit, chunk files are removed by us instead
+                    // so viewers can still fetch the last ones after the stream ends
                     "-dash_segment_type",
                     "webm",
                     // 5 chunk files in the manifest, 10 seconds of media in the manifest
@@ -187,7 +396,7 @@ impl Ffmpeg {
                     "-extra_window_size",
                     "2",
                     "-utc_timing_url",
-                    "https://time.akamai.com/",
+                    &self.utc_timing_url,
                     // template will use media="chunk-stream$RepresentationID$-$Number%05d$.webm"
                     // so the client knows where all the files are without fetching manifest again
                     // we don't want to use template because then the client will expect segments
@@ -206,18 +415,41 @@ impl Ffmpeg {
                     "1",
                     "-ignore_io_errors",
                     "1",
+                    // a new name each run, so segments can be cached forever without
+                    // a restarted encoder's chunk-stream0-00001 colliding with the last one's
+                    "-init_seg_name",
+                    format!("init-stream$RepresentationID$-{}.$ext$", run_id),
+                    "-media_seg_name",
+                    format!(
+                        "chunk-stream$RepresentationID$-{}-$Number%05d$.$ext$",
+                        run_id
+                    ),
                     // "One or more streams in WebM output format. Streaming option will be ignored"
                     // "-streaming",
                     // "0",
                     // "LDash option will be ignored as streaming is not enabled"
                     // "-ldash",
                     // "0",
-                    "stream.mpd",
                 );
+
+                match dash_output {
+                    DashOutput::Dir(_output_dir_path) => {
+                        append!(args, MANIFEST_NAME);
+                    }
+
+                    DashOutput::Http(base_url) => {
+                        append!(
+                            args,
+                            "-method",
+                            "PUT",
+                            format!("{}{}", base_url, MANIFEST_NAME)
+                        );
+                    }
+                }
             }
 
             FfmpegOutput::Rtmp(addr) => {
-                let rtmp_addr = format!("rtmp://{}/{}/{}", addr, stream_path, stream_key);
+                let rtmp_addr = format!("rtmp://{}/{}/{}", addr, STREAM_PATH, STREAM_KEY);
                 append!(args, "-f", "flv", rtmp_addr);
             }
         }
@@ -226,7 +458,7 @@ impl Ffmpeg {
 
         match &self.input {
             FfmpegInput::Rtmp(addr) => {
-                let rtmp_addr = format!("rtmp://{}/{}/{}", addr, stream_path, stream_key);
+                let rtmp_addr = format!("rtmp://{}/{}/{}", addr, STREAM_PATH, STREAM_KEY);
                 info!("ffmpeg listening for rtmp connections at {}", rtmp_addr);
             }
 
@@ -235,16 +467,47 @@ impl Ffmpeg {
             }
         }
 
-        let command = match &self.output {
-            FfmpegOutput::Dash(temp_dir_path) => Command::new("ffmpeg")
-                .args(args)
-                .current_dir(temp_dir_path)
-                .spawn()?,
-            FfmpegOutput::Rtmp(_addr) => Command::new("ffmpeg").args(args).spawn()?,
-        };
+        let mut command = Command::new("ffmpeg");
+        // piped so that stop() can ask it to quit, and so we know what it said before failing
+        command
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let FfmpegOutput::Dash(DashOutput::Dir(output_dir_path)) = &self.output {
+            command.current_dir(output_dir_path);
+        }
+        // so Ctrl-C in our terminal doesn't kill it too, we end it ourselves
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+            command.creation_flags(CREATE_NEW_PROCESS_GROUP);
+        }
+        let mut command = command.spawn()?;
+
+        self.state.set_running(self.info());
+        if let Some(stdout) = command.stdout.take() {
+            let state = self.state.clone();
+            thread::spawn(move || read_progress(stdout, &state));
+        }
+        if let Some(stderr) = command.stderr.take() {
+            let state = self.state.clone();
+            thread::spawn(move || read_output(stderr, &state));
+        }
 
         self.command = Some(command);
 
+        Ok(())
+    }
+
+    /// until ffmpeg exits, an error if it didn't exit cleanly
+    pub async fn wait(&mut self) -> Result<()> {
         loop {
             tokio::time::sleep(Duration::from_millis(500)).await;
 
@@ -252,11 +515,19 @@ impl Ffmpeg {
                 if let Some(command) = self.command.as_mut() {
                     match command.try_wait() {
                         Ok(Some(status)) => {
+                            self.command = None;
+                            self.state.set_stopped();
                             if status.success() {
+                                self.state.set_ended();
                                 info!("ffmpeg exited with: {}", status);
                                 return Ok(());
                             } else {
-                                bail!("ffmpeg exited with: {}", status);
+                                let mut message = format!("ffmpeg exited with: {}", status);
+                                for line in self.state.output_tail() {
+                                    message.push_str("\n    ");
+                                    message.push_str(&line);
+                                }
+                                bail!(message);
                             }
                         }
 
@@ -265,6 +536,7 @@ impl Ffmpeg {
                         }
 
                         Err(e) => {
+                            self.kill();
                             bail!("ffmpeg error attempting to wait: {}", e);
                         }
                     }
@@ -274,19 +546,133 @@ impl Ffmpeg {
             }
         }
     }
-}
 
-impl Drop for Ffmpeg {
-    fn drop(&mut self) {
+    /// asks ffmpeg to quit so it cleans up its segments, killing it if it takes too long
+    pub async fn stop(&mut self) {
+        if let Some(command) = self.command.as_mut() {
+            if let Some(mut stdin) = command.stdin.take() {
+                // same as pressing q in its terminal, rtmp listening ignores it though
+                let _ignore = stdin.write_all(b"q");
+            }
+
+            let started = Instant::now();
+            while started.elapsed() < STOP_TIMEOUT {
+                if let Ok(Some(_)) = command.try_wait() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+
+        self.kill();
+    }
+
+    /// stops ffmpeg for good, as if the input had ended
+    pub async fn finish(&mut self) {
+        self.stop().await;
+        self.state.set_ended();
+    }
+
+    fn kill(&mut self) {
         if let Some(mut command) = self.command.take() {
+            self.state.set_stopped();
             let _ignore = command.kill();
             if let Err(e) = command.wait() {
                 error!("command.wait(): {}", e);
             }
+        }
+    }
+}
+
+/// passes ffmpeg's messages through as they come, and remembers the last lines
+fn read_output<R: Read>(mut output: R, state: &EncoderState) {
+    let mut buffer = [0; 4096];
+    let mut line = Vec::new();
+
+    loop {
+        let len = match output.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+
+        let mut stderr = io::stderr();
+        let _ignore = stderr.write_all(&buffer[..len]);
+        let _ignore = stderr.flush();
+
+        for &byte in &buffer[..len] {
+            match byte {
+                b'\n' => {
+                    let text = String::from_utf8_lossy(&line).trim().to_string();
+                    if !text.is_empty() {
+                        state.push_output(text);
+                    }
+                    line.clear();
+                }
+                // -stats rewrites its line, only keep the last one
+                b'\r' => line.clear(),
+                _ => line.push(byte),
+            }
+        }
+    }
+}
+
+fn read_progress<R: Read>(output: R, state: &EncoderState) {
+    let mut stats = EncoderStats::default();
+
+    for line in BufReader::new(output).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+
+        // values look like "30.00", "1234.5kbits/s", "1.01x" or "N/A"
+        let number = value
+            .trim_end_matches(|c: char| c.is_ascii_alphabetic() || c == '/')
+            .parse::<f64>()
+            .ok();
+
+        match key {
+            "frame" => stats.frame = number.unwrap_or_default() as u64,
+            "fps" => stats.fps = number.unwrap_or_default(),
+            "bitrate" => stats.bitrate_kbps = number,
+            "speed" => stats.speed = number,
+            "drop_frames" => stats.dropped_frames = number.unwrap_or_default() as u64,
+            "dup_frames" => stats.duplicated_frames = number.unwrap_or_default() as u64,
+            // end of a block
+            "progress" => state.set_stats(stats.clone()),
+            _ => {}
+        }
+    }
+}
 
-            // gross, windows doesn't really wait here
-            // temp folder is still locked so it can't be removed
-            thread::sleep(Duration::from_millis(1000));
+impl Drop for Ffmpeg {
+    fn drop(&mut self) {
+        if self.is_running() {
+            self.kill();
+
+            if let FfmpegOutput::Dash(DashOutput::Dir(_)) = self.output {
+                // gross, windows doesn't really wait here
+                // temp folder is still locked so it can't be removed
+                thread::sleep(Duration::from_millis(1000));
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_ids() {
+        let first = next_run_id();
+        let second = next_run_id();
+        assert!(second > first);
+        assert!(first > 1_000_000_000 * 1_000_000_000);
+    }
+}
@@ -2,8 +2,9 @@ use crate::error::*;
 use std::{
     env,
     path::{Path, PathBuf},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use subtle::ConstantTimeEq;
 
 pub fn get_absolute_path(path: &Path) -> PathBuf {
     if path.is_relative() {
@@ -17,32 +18,159 @@ pub fn get_absolute_path(path: &Path) -> PathBuf {
 
 pub fn parse_duration(input: &str) -> Result<Duration> {
     let parts: Vec<_> = input.split(':').collect();
-    let secs = match parts.as_slice() {
+    let (whole_minutes, seconds) = match parts.as_slice() {
         [hours, minutes, seconds] => {
             let hours: u32 = hours.parse()?;
             let minutes: u32 = minutes.parse()?;
-            let seconds: f32 = seconds.parse()?;
 
-            seconds + (minutes * 60 + hours * 60 * 60) as f32
+            (minutes + hours * 60, seconds)
         }
 
         [minutes, seconds] => {
             let minutes: u32 = minutes.parse()?;
-            let seconds: f32 = seconds.parse()?;
 
-            seconds + (minutes * 60) as f32
+            (minutes, seconds)
         }
 
-        [seconds] => {
-            let seconds: f32 = seconds.parse()?;
-
-            seconds
-        }
+        [seconds] => (0, seconds),
 
         _ => {
             bail!("couldn't convert {:?} to seconds", input);
         }
     };
 
-    Ok(Duration::from_secs_f32(secs))
+    let seconds: f32 = seconds.parse()?;
+    if !(seconds >= 0.0 && seconds.is_finite()) {
+        bail!("{:?} isn't a time", input);
+    }
+
+    Ok(Duration::from_secs_f32(
+        seconds + (whole_minutes * 60) as f32,
+    ))
+}
+
+/// compares passwords in constant time
+pub fn secrets_match(given: &str, expected: &str) -> bool {
+    given.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+pub struct UtcTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millis: u32,
+}
+
+impl UtcTime {
+    pub fn now() -> Self {
+        Self::from_system_time(SystemTime::now())
+    }
+
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let days = (secs / 86400) as i64;
+        let secs_of_day = (secs % 86400) as u32;
+
+        // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+
+    /// 20200102
+    pub fn to_basic_date(&self) -> String {
+        format!("{:04}{:02}{:02}", self.year, self.month, self.day)
+    }
+
+    /// 20200102T030405Z
+    pub fn to_basic_date_time(&self) -> String {
+        format!(
+            "{}T{:02}{:02}{:02}Z",
+            self.to_basic_date(),
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// 2020-01-02T03:04:05.678Z
+    pub fn to_iso8601(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+
+    /// 02/Jan/2020:03:04:05 +0000
+    pub fn to_clf(&self) -> String {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        format!(
+            "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("1:30").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_duration("01:00:05").unwrap(),
+            Duration::from_secs(3605)
+        );
+        assert_eq!(parse_duration("0.5").unwrap(), Duration::from_millis(500));
+
+        for input in &["", "soon", "-5", "1:2:3:4", "inf", "NaN", "1:-30"] {
+            assert!(parse_duration(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn secrets() {
+        assert!(secrets_match("hunter2", "hunter2"));
+        assert!(!secrets_match("hunter3", "hunter2"));
+        assert!(!secrets_match("hunter", "hunter2"));
+        assert!(!secrets_match("", "hunter2"));
+    }
+
+    #[test]
+    fn utc_time() {
+        let time = UtcTime::from_system_time(UNIX_EPOCH + Duration::from_millis(951_782_400_123));
+        assert_eq!(time.to_iso8601(), "2000-02-29T00:00:00.123Z");
+        assert_eq!(time.to_basic_date_time(), "20000229T000000Z");
+        assert_eq!(time.to_clf(), "29/Feb/2000:00:00:00 +0000");
+    }
 }
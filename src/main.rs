@@ -2,22 +2,48 @@ mod error;
 mod ffmpeg;
 mod helpers;
 mod logger;
+mod output;
+mod publish;
+mod store;
+mod supervisor;
 mod web;
 
 use crate::{
     error::*,
-    ffmpeg::{Ffmpeg, FfmpegInput, FfmpegOutput},
+    ffmpeg::{DashOutput, EncoderState, Ffmpeg, FfmpegInput, FfmpegOutput},
     helpers::*,
+    output::{OnExisting, OutputDir},
+    publish::{PublishAuth, PublishTarget, Publisher},
+    store::{SegmentStore, Segments},
+    supervisor::{Command as SupervisorCommand, Control, Supervisor},
+    web::{AuthOptions, Chat, Cidr, Drained, LimitOptions, PageOptions, StreamOptions, WebOptions},
 };
-use clap::{crate_name, crate_version, Arg, ArgAction, Command};
+use clap::{crate_name, crate_version, Arg, ArgAction, ArgMatches, Command};
 use futures::{channel::mpsc, stream::StreamExt, FutureExt};
 use log::{debug, error, info};
 use std::{
+    env,
     net::{IpAddr, SocketAddr},
     path::PathBuf,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
+/// one stream's encoder and what's shared with the server
+struct Stream {
+    name: Option<String>,
+    segments: Segments,
+    encoder_state: Arc<EncoderState>,
+    supervisor: Supervisor,
+    control: Control,
+    drained: Drained,
+    /// served by us, every stream has the same kind of output
+    dash: bool,
+    remote: Option<SocketAddr>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     #[allow(unused_mut)]
@@ -31,6 +57,20 @@ async fn main() -> Result<()> {
                 .action(ArgAction::Count),
         )
         .arg(Arg::new("file").help("Play a file instead of starting an rtmp server"))
+        .arg(
+            Arg::new("stream")
+                .long("stream")
+                .help("Serve a named stream at /live/<name>/, use multiple times for more")
+                .long_help(
+                    "Serve a named stream at /live/<name>/, use multiple times for more.\nEach \
+                     stream plays its file, or without one listens for rtmp on the next port \
+                     counting up from --rtmp-port. The page at / lists the streams.",
+                )
+                .value_name("name[=file]")
+                .num_args(1)
+                .action(ArgAction::Append)
+                .conflicts_with_all(["file", "subtitles", "remote-rtmp", "publish"]),
+        )
         .arg(
             Arg::new("seek")
                 .alias("time")
@@ -169,6 +209,368 @@ async fn main() -> Result<()> {
                 .value_name("bitrate")
                 .num_args(1)
                 .default_value("128k"),
+        )
+        .arg(
+            Arg::new("utc-timing-url")
+                .long("utc-timing-url")
+                .help("Sets where players sync their clock from")
+                .long_help(
+                    "Sets where players sync their clock from.\nRelative urls are resolved \
+                     against the manifest, so the default uses this server's /time endpoint.\nUse \
+                     https://time.akamai.com/?iso for an external source.",
+                )
+                .value_name("url")
+                .num_args(1)
+                .default_value("time"),
+        )
+        .arg(
+            Arg::new("access-log")
+                .long("access-log")
+                .help("Append http requests to a file in combined log format")
+                .value_name("file")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("player-cdn")
+                .long("player-cdn")
+                .help("Load the player script from this url instead of the bundled copy")
+                .value_name("url")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("title")
+                .long("title")
+                .help("Sets the player page's title")
+                .value_name("title")
+                .num_args(1)
+                .default_value("DASH Stream"),
+        )
+        .arg(
+            Arg::new("description")
+                .long("description")
+                .help("Sets the player page's description")
+                .value_name("text")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("poster")
+                .long("poster")
+                .help("Show this image before the stream starts playing")
+                .value_name("url")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("logo")
+                .long("logo")
+                .help("Show this image in the corner of the player page")
+                .value_name("url")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("background-color")
+                .long("background-color")
+                .help("Sets the player page's background color")
+                .value_name("color")
+                .num_args(1)
+                .default_value("#000000"),
+        )
+        .arg(
+            Arg::new("text-color")
+                .long("text-color")
+                .help("Sets the player page's text color")
+                .value_name("color")
+                .num_args(1)
+                .default_value("#ffffff"),
+        )
+        .arg(
+            Arg::new("accent-color")
+                .long("accent-color")
+                .help("Sets the color of chat nicknames")
+                .value_name("color")
+                .num_args(1)
+                .default_value("#bf94ff"),
+        )
+        .arg(
+            Arg::new("latency-target")
+                .long("latency-target")
+                .help("How far behind live players try to stay, in seconds")
+                .long_help(
+                    "How far behind live players try to stay, in seconds.\nPlayers speed up or \
+                     slow down a little to get there, and only jump when they fall far behind.",
+                )
+                .value_name("seconds")
+                .num_args(1)
+                .default_value("3"),
+        )
+        .arg(
+            Arg::new("web-root")
+                .long("web-root")
+                .help("Serve files from this directory over the built-in ones")
+                .long_help(
+                    "Serve files from this directory over the built-in ones.\nAn index.html in \
+                     it replaces the player page, with {{title}}, {{manifest_url}}, {{config}} \
+                     and the other page settings filled in.",
+                )
+                .value_name("dir")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("chat")
+                .long("chat")
+                .help("Show a live chat next to the player")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("chat-moderator-password")
+                .long("chat-moderator-password")
+                .help("Lets chat users who know this password mute, delete and set slow mode")
+                .value_name("password")
+                .num_args(1)
+                .requires("chat"),
+        )
+        .arg(
+            Arg::new("basic-auth")
+                .long("basic-auth")
+                .help("Require http basic auth to watch")
+                .value_name("user:password")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("admin-password")
+                .long("admin-password")
+                .help("Enable the /admin dashboard for controlling the encoder")
+                .long_help(
+                    "Enable the /admin dashboard and the /api/admin endpoints for controlling the \
+                     encoder.\nSend the password as a bearer token, or with basic auth and any \
+                     user name.",
+                )
+                .value_name("password")
+                .num_args(1)
+                .conflicts_with("remote-rtmp"),
+        )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .help("Require a password to watch, asked for on the player page")
+                .value_name("password")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("viewers-file")
+                .long("viewers-file")
+                .help("Only let invited viewers watch, using signed links")
+                .long_help(
+                    "Only let invited viewers watch, using signed links.\nThe file has one \
+                     viewer name per line, a link for each is printed on startup and when they're \
+                     added.\nRemoving a name revokes their link, and links from before adding it \
+                     back stay revoked.",
+                )
+                .value_name("file")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("link-secret")
+                .long("link-secret")
+                .help("Sets the key used to sign viewer links")
+                .long_help(
+                    "Sets the key used to sign viewer links.\nWithout one a random key is used, \
+                     so links stop working when the server restarts.",
+                )
+                .value_name("secret")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("link-lifetime")
+                .long("link-lifetime")
+                .help("Sets how long viewer links and password logins last")
+                .value_name("hh:mm:ss")
+                .num_args(1)
+                .default_value("24:00:00"),
+        )
+        .arg(
+            Arg::new("public-url")
+                .long("public-url")
+                .help("Sets the url viewers reach the server at")
+                .long_help(
+                    "Sets the url viewers reach the server at.\nUsed in front of the links printed \
+                     on startup, like https://example.com/stream/.",
+                )
+                .value_name("url")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("trusted-proxy")
+                .long("trusted-proxy")
+                .help("Believe X-Forwarded-For and Forwarded headers from these addresses")
+                .long_help(
+                    "Believe X-Forwarded-For and Forwarded headers from these addresses.\nTakes \
+                     ips or ranges like 10.0.0.0/8, use multiple times for more than one.\nNone \
+                     are trusted by default, use 127.0.0.1 and ::1 for a proxy on this machine.",
+                )
+                .value_name("cidr")
+                .num_args(1)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("max-viewers")
+                .long("max-viewers")
+                .help("Turn away new viewers once this many are watching")
+                .value_name("count")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("max-viewers-per-address")
+                .long("max-viewers-per-address")
+                .help("How many of the --max-viewers places one address can take")
+                .value_name("count")
+                .default_value("5")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("allow")
+                .long("allow")
+                .help("Only let these addresses in, use multiple times for more than one")
+                .value_name("cidr")
+                .num_args(1)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("deny")
+                .long("deny")
+                .help("Keep these addresses out, use multiple times for more than one")
+                .value_name("cidr")
+                .num_args(1)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("rate-limit")
+                .long("rate-limit")
+                .help("Sets how many requests per second each address can make")
+                .value_name("requests")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("output-dir")
+                .long("output-dir")
+                .help("Write segments to this directory instead of keeping them in memory")
+                .value_name("dir")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("on-existing")
+                .long("on-existing")
+                .help("What to do if the output dir already has files in it")
+                .value_name("action")
+                .num_args(1)
+                .value_parser(["refuse", "clean", "resume"])
+                .default_value("refuse")
+                .requires("output-dir"),
+        )
+        .arg(
+            Arg::new("max-restarts")
+                .long("max-restarts")
+                .help("How many times ffmpeg is restarted after failing before giving up")
+                .long_help(
+                    "How many times ffmpeg is restarted after failing before giving up.\nRestarts \
+                     wait longer each time, from 1 second up to a minute, and only failures in \
+                     the last 10 minutes count. 0 exits on the first failure.",
+                )
+                .value_name("count")
+                .num_args(1)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::new("drain-timeout")
+                .long("drain-timeout")
+                .help("How long viewers get to finish watching when the stream ends")
+                .long_help(
+                    "How long viewers get to finish watching when the stream ends.\nWhen the \
+                     input ends or on Ctrl-C, new viewers are turned away and the server waits \
+                     until everyone watching has the last segment, or this long at most. Press \
+                     Ctrl-C again to exit right away.",
+                )
+                .value_name("hh:mm:ss")
+                .num_args(1)
+                .default_value("30"),
+        )
+        .arg(
+            Arg::new("publish")
+                .long("publish")
+                .help("Also push segments and the manifest to an origin server or s3 bucket")
+                .long_help(
+                    "Also push segments and the manifest to an origin server or s3 bucket.\nUse \
+                     http://host/path/ for a WebDAV or nginx style server that accepts PUT and \
+                     DELETE, or s3://bucket/prefix for s3 compatible storage.",
+                )
+                .value_name("url")
+                .num_args(1)
+                .conflicts_with("remote-rtmp"),
+        )
+        .arg(
+            Arg::new("publish-method")
+                .long("publish-method")
+                .help("Sets the http method used to upload files to an http origin")
+                .value_name("method")
+                .num_args(1)
+                .value_parser(["put", "post"])
+                .default_value("put")
+                .requires("publish"),
+        )
+        .arg(
+            Arg::new("publish-auth")
+                .long("publish-auth")
+                .help("Use basic auth when publishing to an http origin")
+                .value_name("user:password")
+                .num_args(1)
+                .requires("publish"),
+        )
+        .arg(
+            Arg::new("publish-token")
+                .long("publish-token")
+                .help("Use a bearer token when publishing to an http origin")
+                .value_name("token")
+                .num_args(1)
+                .requires("publish")
+                .conflicts_with("publish-auth"),
+        )
+        .arg(
+            Arg::new("publish-retries")
+                .long("publish-retries")
+                .help("Sets how many times a failed upload or delete is retried")
+                .value_name("count")
+                .num_args(1)
+                .default_value("3")
+                .requires("publish"),
+        )
+        .arg(
+            Arg::new("s3-endpoint")
+                .long("s3-endpoint")
+                .help("Sets the s3 endpoint, for example http://127.0.0.1:9000 for MinIO")
+                .value_name("url")
+                .num_args(1)
+                .default_value("https://s3.amazonaws.com/"),
+        )
+        .arg(
+            Arg::new("s3-region")
+                .long("s3-region")
+                .help("Sets the s3 region")
+                .value_name("region")
+                .num_args(1)
+                .default_value("us-east-1"),
+        )
+        .arg(
+            Arg::new("s3-access-key")
+                .long("s3-access-key")
+                .help("Sets the s3 access key, defaults to $AWS_ACCESS_KEY_ID")
+                .value_name("key")
+                .num_args(1),
+        )
+        .arg(
+            Arg::new("s3-secret-key")
+                .long("s3-secret-key")
+                .help("Sets the s3 secret key, defaults to $AWS_SECRET_ACCESS_KEY")
+                .value_name("key")
+                .num_args(1),
         );
 
     #[cfg(feature = "tls")]
@@ -208,6 +610,76 @@ async fn main() -> Result<()> {
     logger::initialize(cfg!(debug_assertions) || verbose >= 1, verbose > 1);
 
     let log_http = verbose >= 1;
+    let access_log_path = matches.get_one::<String>("access-log").map(PathBuf::from);
+    let player_cdn = matches.get_one::<String>("player-cdn").cloned();
+    let admin_password = matches.get_one::<String>("admin-password").cloned();
+
+    let page = PageOptions {
+        title: matches.get_one::<String>("title").unwrap().clone(),
+        description: matches.get_one::<String>("description").cloned(),
+        poster: matches.get_one::<String>("poster").cloned(),
+        logo: matches.get_one::<String>("logo").cloned(),
+        background_color: matches
+            .get_one::<String>("background-color")
+            .unwrap()
+            .clone(),
+        text_color: matches.get_one::<String>("text-color").unwrap().clone(),
+        accent_color: matches.get_one::<String>("accent-color").unwrap().clone(),
+        latency_target: matches
+            .get_one::<String>("latency-target")
+            .unwrap()
+            .parse()?,
+        web_root: matches.get_one::<String>("web-root").map(PathBuf::from),
+    };
+
+    let auth = AuthOptions {
+        basic_auth: matches.get_one::<String>("basic-auth").map(|auth| {
+            let (user, password) = auth.split_once(':').unwrap_or((auth, ""));
+            (user.to_string(), password.to_string())
+        }),
+        password: matches.get_one::<String>("password").cloned(),
+        link_secret: matches.get_one::<String>("link-secret").cloned(),
+        viewers_file: matches.get_one::<String>("viewers-file").map(PathBuf::from),
+        link_lifetime: parse_duration(matches.get_one::<String>("link-lifetime").unwrap())?,
+    };
+
+    let public_url = match matches.get_one::<String>("public-url") {
+        Some(public_url) => {
+            let mut public_url: reqwest::Url = public_url.parse()?;
+            if !public_url.path().ends_with('/') {
+                public_url.set_path(&format!("{}/", public_url.path()));
+            }
+            Some(public_url.to_string())
+        }
+        None => None,
+    };
+
+    let trusted_proxies = parse_cidrs(&matches, "trusted-proxy")?;
+
+    let limits = LimitOptions {
+        max_viewers: matches
+            .get_one::<String>("max-viewers")
+            .map(|count| count.parse())
+            .transpose()?,
+        max_viewers_per_address: matches
+            .get_one::<String>("max-viewers-per-address")
+            .unwrap()
+            .parse()?,
+        allow: parse_cidrs(&matches, "allow")?,
+        deny: parse_cidrs(&matches, "deny")?,
+        rate_limit: matches
+            .get_one::<String>("rate-limit")
+            .map(|count| count.parse())
+            .transpose()?,
+    };
+
+    let chat = matches.get_flag("chat").then(|| {
+        Chat::new(
+            matches
+                .get_one::<String>("chat-moderator-password")
+                .cloned(),
+        )
+    });
 
     let tls_cert_path = matches
         .try_get_one::<String>("tls-cert")
@@ -225,18 +697,21 @@ async fn main() -> Result<()> {
         || tls_cert_path.is_some()
         || tls_key_path.is_some();
 
-    let input = if let Some(path) = matches.get_one::<String>("file") {
+    let rtmp_ip: IpAddr = matches.get_one::<String>("rtmp-ip").unwrap().parse()?;
+    let rtmp_port: u16 = matches.get_one::<String>("rtmp-port").unwrap().parse()?;
+
+    let inputs = if let Some(streams) = matches.get_many::<String>("stream") {
+        parse_streams(streams, rtmp_ip, rtmp_port)?
+    } else if let Some(path) = matches.get_one::<String>("file") {
         let path = PathBuf::from(path);
         let seek = if let Some(seek) = matches.get_one::<String>("seek") {
             Some(parse_duration(seek)?)
         } else {
             None
         };
-        FfmpegInput::File { path, seek }
+        vec![(None, FfmpegInput::File { path, seek })]
     } else {
-        let rtmp_ip: IpAddr = matches.get_one::<String>("rtmp-ip").unwrap().parse()?;
-        let rtmp_port: u16 = matches.get_one::<String>("rtmp-port").unwrap().parse()?;
-        FfmpegInput::Rtmp(SocketAddr::new(rtmp_ip, rtmp_port))
+        vec![(None, FfmpegInput::Rtmp(SocketAddr::new(rtmp_ip, rtmp_port)))]
     };
 
     let http_ip: IpAddr = matches.get_one::<String>("http-ip").unwrap().parse()?;
@@ -267,89 +742,251 @@ async fn main() -> Result<()> {
 
     let subtitles_path = matches.get_one::<String>("subtitles").map(Into::into);
 
-    let temp_dir = tempfile::Builder::new()
-        .prefix(&format!(".{}", crate_name!()))
-        .tempdir()?;
+    let utc_timing_url = matches
+        .get_one::<String>("utc-timing-url")
+        .unwrap()
+        .to_string();
 
-    let temp_dir_path = temp_dir.path().to_owned();
-    debug!("created temp dir {:?}", temp_dir_path);
+    let mut output_dirs = Vec::new();
+    let mut streams = Vec::new();
+    for (name, input) in inputs {
+        // named streams each get a directory of their own
+        let output_dir = if let Some(path) = matches.get_one::<String>("output-dir") {
+            let on_existing: OnExisting =
+                matches.get_one::<String>("on-existing").unwrap().parse()?;
+            let mut path = PathBuf::from(path);
+            if let Some(name) = &name {
+                path.push(name);
+            }
+            Some(OutputDir::persistent(path, on_existing)?)
+        } else {
+            None
+        };
+
+        let segments = match &output_dir {
+            Some(output_dir) => Segments::Dir(output_dir.path().to_owned()),
+            None => Segments::Memory(SegmentStore::new()),
+        };
+        output_dirs.extend(output_dir);
+
+        let output = if let Some(addr) = matches.get_one::<String>("remote-rtmp") {
+            FfmpegOutput::Rtmp(addr.parse()?)
+        } else {
+            match &segments {
+                Segments::Dir(output_dir_path) => {
+                    FfmpegOutput::Dash(DashOutput::Dir(output_dir_path.clone()))
+                }
+                Segments::Memory(store) => {
+                    FfmpegOutput::Dash(DashOutput::Http(web::start_ingest(store.clone())?))
+                }
+            }
+        };
+
+        streams.push((name, input, segments, output));
+    }
 
-    let output = if let Some(addr) = matches.get_one::<String>("remote-rtmp") {
-        FfmpegOutput::Rtmp(addr.parse()?)
+    let publish_target = if let Some(url) = matches.get_one::<String>("publish") {
+        Some(parse_publish_target(url, &matches)?)
     } else {
-        FfmpegOutput::Dash(temp_dir_path)
+        None
     };
+    let publish_retries: usize = matches
+        .get_one::<String>("publish-retries")
+        .unwrap()
+        .parse()?;
+
+    let max_restarts: usize = matches.get_one::<String>("max-restarts").unwrap().parse()?;
+    let drain_timeout = parse_duration(matches.get_one::<String>("drain-timeout").unwrap())?;
 
     let (sender, mut receiver) = mpsc::unbounded();
+    let (end_sender, mut end_requests) = mpsc::unbounded();
 
     {
         let sender = sender.clone();
+        let mut stopping = false;
         ctrlc::set_handler(move || {
-            info!("stopping...");
-            let _ignore = sender.unbounded_send(());
+            if stopping {
+                info!("exiting");
+                let _ignore = sender.unbounded_send(());
+            } else {
+                info!("stopping, waiting for viewers to finish, press Ctrl-C again to exit now");
+                stopping = true;
+                let _ignore = end_sender.unbounded_send(());
+            }
         })
         .expect("Error setting Ctrl-C handler");
     }
 
-    match &output {
-        FfmpegOutput::Dash(temp_dir_path) => {
-            // only start http server if we're going to use it
-
-            let temp_dir_path = temp_dir_path.clone();
-            let sender = sender.clone();
-
-            let f = async move {
-                if let Err(e) = web::start(
-                    SocketAddr::new(http_ip, http_port),
-                    temp_dir_path,
-                    log_http,
-                    tls,
-                    tls_cert_path,
-                    tls_key_path,
-                )
-                .await
-                {
-                    error!("web: {}", e);
-                }
-                let _ignore = sender.unbounded_send(());
+    let streams: Vec<Stream> = streams
+        .into_iter()
+        .map(|(name, input, segments, output)| {
+            let encoder_state: Arc<EncoderState> = Default::default();
+            let dash = matches!(output, FfmpegOutput::Dash(_));
+            let remote = match &output {
+                FfmpegOutput::Rtmp(addr) => Some(*addr),
+                FfmpegOutput::Dash(_) => None,
+            };
+
+            let ffmpeg = Ffmpeg {
+                command: None,
+                verbose: verbose >= 1,
+                state: encoder_state.clone(),
+                input,
+                output,
+                cpu_used,
+                framerate,
+                crf,
+                video_bitrate: video_bitrate.clone(),
+                video_resolution: video_resolution.clone(),
+                audio_bitrate: audio_bitrate.clone(),
+                audio_sample_rate: audio_sample_rate.clone(),
+                subtitles_path: subtitles_path.clone(),
+                utc_timing_url: utc_timing_url.clone(),
+            };
+
+            let label = match &name {
+                Some(name) => format!("ffmpeg {}", name),
+                None => "ffmpeg".to_string(),
+            };
+            let (supervisor, control) =
+                Supervisor::new(label, ffmpeg, segments.clone(), max_restarts);
+
+            Stream {
+                name,
+                segments,
+                encoder_state,
+                supervisor,
+                control,
+                drained: Drained::default(),
+                dash,
+                remote,
             }
-            .boxed();
-            tokio::spawn(f);
-        }
+        })
+        .collect();
+
+    // every stream has the same kind of output
+    let dash = streams[0].dash;
 
-        FfmpegOutput::Rtmp(addr) => {
-            info!("sending to remote rtmp at {}", addr);
+    if dash {
+        // only start http server if we're going to use it
+
+        let options = WebOptions {
+            addr: SocketAddr::new(http_ip, http_port),
+            streams: streams
+                .iter()
+                .map(|stream| StreamOptions {
+                    name: stream.name.clone(),
+                    segments: stream.segments.clone(),
+                    encoder: stream.encoder_state.clone(),
+                    control: stream.control.clone(),
+                    drained: stream.drained.clone(),
+                })
+                .collect(),
+            log: log_http,
+            access_log_path,
+            public_url,
+            tls,
+            tls_cert_path,
+            tls_key_path,
+            chat,
+            auth,
+            trusted_proxies,
+            limits,
+            admin_password,
+            player_cdn,
+            page,
+        };
+        let sender = sender.clone();
+
+        let f = async move {
+            if let Err(e) = web::start(options).await {
+                error!("web: {}", e);
+            }
+            let _ignore = sender.unbounded_send(());
         }
+        .boxed();
+        tokio::spawn(f);
+    } else if let Some(addr) = &streams[0].remote {
+        info!("sending to remote rtmp at {}", addr);
     }
 
-    {
-        let mut ffmpeg = Ffmpeg {
-            command: None,
-            verbose: verbose >= 1,
-            input,
-            output,
-            cpu_used,
-            framerate,
-            crf,
-            video_bitrate,
-            video_resolution,
-            audio_bitrate,
-            audio_sample_rate,
-            subtitles_path,
+    let publisher_handle = if let (Some(target), true) = (publish_target, dash) {
+        let publisher = Publisher {
+            client: reqwest::Client::new(),
+            target,
+            retries: publish_retries,
+            source: streams[0].segments.clone(),
         };
 
-        tokio::spawn(async move {
-            if let Err(e) = ffmpeg.run().await {
-                error!("ffmpeg: {}", e);
-            } else {
-                info!(
-                    "ffmpeg exited cleanly, sleeping for a bit so that the video finishes \
-                     downloading"
-                );
-                // exited cleanly, let's keep hosting the files until the video stops
-                tokio::time::sleep(Duration::from_secs(6)).await;
+        let sender = sender.clone();
+        let (f, handle) = async move {
+            if let Err(e) = publisher.run().await {
+                error!("publish: {}", e);
             }
             let _ignore = sender.unbounded_send(());
+        }
+        .remote_handle();
+        tokio::spawn(f);
+
+        Some(handle)
+    } else {
+        None
+    };
+
+    // the first ctrl-c ends every stream, and they exit once drained
+    let controls: Vec<_> = streams
+        .iter()
+        .map(|stream| stream.control.clone())
+        .collect();
+    tokio::spawn(async move {
+        if end_requests.next().await.is_some() {
+            for control in controls {
+                // streams that already ended don't need it
+                let _ignore = control.send(SupervisorCommand::End).await;
+            }
+        }
+    });
+
+    // keep serving until the last stream is done
+    let running = Arc::new(AtomicUsize::new(streams.len()));
+    let gave_up = Arc::new(AtomicBool::new(false));
+    for Stream {
+        segments,
+        encoder_state,
+        supervisor,
+        drained,
+        ..
+    } in streams
+    {
+        let running = running.clone();
+        let gave_up = gave_up.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let label = supervisor.label().to_string();
+            if let Err(e) = supervisor.run().await {
+                error!("{}: {}", label, e);
+                gave_up.store(true, Ordering::SeqCst);
+                // viewers still get to watch what was made
+                encoder_state.set_ended();
+            }
+
+            if dash {
+                info!("{} ended, waiting for viewers to finish", label);
+                if tokio::time::timeout(drain_timeout, drained.wait())
+                    .await
+                    .is_err()
+                {
+                    info!(
+                        "{}: viewers still watching after {:?}",
+                        label, drain_timeout
+                    );
+                }
+            }
+            segments.clear();
+
+            if running.fetch_sub(1, Ordering::SeqCst) == 1 {
+                let _ignore = sender.unbounded_send(());
+            }
         });
     }
 
@@ -357,9 +994,138 @@ async fn main() -> Result<()> {
     receiver.next().await;
     debug!("exiting");
 
-    if let Err(e) = temp_dir.close() {
-        error!("temp_dir: {}", e);
+    drop(publisher_handle);
+
+    for output_dir in output_dirs {
+        if let Err(e) = output_dir.close() {
+            error!("output_dir: {}", e);
+        }
+    }
+
+    if gave_up.load(Ordering::SeqCst) {
+        bail!("ffmpeg kept failing");
     }
 
     Ok(())
 }
+
+/// `name[=file]` for each --stream, the ones without a file take turns on rtmp ports
+fn parse_streams<'a>(
+    streams: impl Iterator<Item = &'a String>,
+    rtmp_ip: IpAddr,
+    rtmp_port: u16,
+) -> Result<Vec<(Option<String>, FfmpegInput)>> {
+    let mut inputs: Vec<(Option<String>, FfmpegInput)> = Vec::new();
+    let mut next_rtmp_port = Some(rtmp_port);
+
+    for stream in streams {
+        let (name, file) = match stream.split_once('=') {
+            Some((name, file)) => (name, Some(file)),
+            None => (stream.as_str(), None),
+        };
+
+        // used in urls and directory names
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            bail!(
+                "stream name {:?} can only have letters, numbers, - and _",
+                name
+            );
+        }
+        if inputs
+            .iter()
+            .any(|(other, _)| other.as_deref() == Some(name))
+        {
+            bail!("stream {:?} is given twice", name);
+        }
+
+        let input = match file {
+            Some(file) => FfmpegInput::File {
+                path: PathBuf::from(file),
+                seek: None,
+            },
+            None => {
+                let port = next_rtmp_port.chain_err(|| "ran out of rtmp ports")?;
+                next_rtmp_port = port.checked_add(1);
+                FfmpegInput::Rtmp(SocketAddr::new(rtmp_ip, port))
+            }
+        };
+
+        inputs.push((Some(name.to_string()), input));
+    }
+
+    Ok(inputs)
+}
+
+fn parse_cidrs(matches: &ArgMatches, name: &str) -> Result<Vec<Cidr>> {
+    matches
+        .get_many::<String>(name)
+        .unwrap_or_default()
+        .map(|cidr| cidr.parse())
+        .collect()
+}
+
+fn parse_publish_target(url: &str, matches: &ArgMatches) -> Result<PublishTarget> {
+    if let Some(rest) = url.strip_prefix("s3://") {
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            bail!("no bucket in {:?}", url);
+        }
+
+        let access_key = matches
+            .get_one::<String>("s3-access-key")
+            .cloned()
+            .or_else(|| env::var("AWS_ACCESS_KEY_ID").ok())
+            .chain_err(|| "no s3 access key, use --s3-access-key or $AWS_ACCESS_KEY_ID")?;
+        let secret_key = matches
+            .get_one::<String>("s3-secret-key")
+            .cloned()
+            .or_else(|| env::var("AWS_SECRET_ACCESS_KEY").ok())
+            .chain_err(|| "no s3 secret key, use --s3-secret-key or $AWS_SECRET_ACCESS_KEY")?;
+
+        Ok(PublishTarget::S3 {
+            endpoint: matches.get_one::<String>("s3-endpoint").unwrap().parse()?,
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            region: matches.get_one::<String>("s3-region").unwrap().to_string(),
+            access_key,
+            secret_key,
+        })
+    } else {
+        let mut base_url: reqwest::Url = url.parse()?;
+        // so that joining file names keeps the last path segment
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        let method = match matches
+            .get_one::<String>("publish-method")
+            .unwrap()
+            .as_str()
+        {
+            "post" => reqwest::Method::POST,
+            _ => reqwest::Method::PUT,
+        };
+
+        let auth = if let Some(auth) = matches.get_one::<String>("publish-auth") {
+            let (user, password) = auth.split_once(':').unwrap_or((auth, ""));
+            Some(PublishAuth::Basic {
+                user: user.to_string(),
+                password: password.to_string(),
+            })
+        } else {
+            matches
+                .get_one::<String>("publish-token")
+                .map(|token| PublishAuth::Bearer(token.to_string()))
+        };
+
+        Ok(PublishTarget::Http {
+            base_url,
+            method,
+            auth,
+        })
+    }
+}
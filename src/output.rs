@@ -0,0 +1,264 @@
+use crate::error::*;
+use futures::{future::RemoteHandle, FutureExt};
+use log::*;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+const LOCK_FILE_NAME: &str = ".dash-live-stream.lock";
+
+// the lock file is rewritten this often while we're running
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// a lock file that hasn't been touched for this long belongs to a dead process
+const STALE_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnExisting {
+    /// refuse to start if the directory isn't empty
+    Refuse,
+    /// remove everything inside the directory before starting
+    Clean,
+    /// keep existing files and write over them
+    Resume,
+}
+
+impl FromStr for OnExisting {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "refuse" => Ok(OnExisting::Refuse),
+            "clean" => Ok(OnExisting::Clean),
+            "resume" => Ok(OnExisting::Resume),
+            _ => bail!("unknown existing directory action {:?}", s),
+        }
+    }
+}
+
+pub struct OutputDir {
+    path: PathBuf,
+    heartbeat_handle: Option<RemoteHandle<()>>,
+}
+
+impl OutputDir {
+    /// user specified directory that is left in place when we exit
+    pub fn persistent(path: PathBuf, on_existing: OnExisting) -> Result<Self> {
+        if path.exists() {
+            if !path.is_dir() {
+                bail!("output dir {:?} is not a directory", path);
+            }
+        } else {
+            fs::create_dir_all(&path)?;
+            debug!("created output dir {:?}", path);
+        }
+
+        // before looking inside, so two instances can't both clean it
+        take_lock(&path)?;
+        let output_dir = Self::locked(path);
+
+        if let Err(e) = output_dir.prepare(on_existing) {
+            let _ignore = output_dir.close();
+            return Err(e);
+        }
+
+        Ok(output_dir)
+    }
+
+    fn prepare(&self, on_existing: OnExisting) -> Result<()> {
+        let entries = fs::read_dir(&self.path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .map(|entry| entry.file_name() != Some(LOCK_FILE_NAME.as_ref()))
+                    .unwrap_or(true)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        match on_existing {
+            OnExisting::Refuse => {
+                bail!(
+                    "output dir {:?} is not empty, use --on-existing clean or resume",
+                    self.path
+                );
+            }
+
+            OnExisting::Clean => {
+                info!("cleaning output dir {:?}", self.path);
+                for entry in entries {
+                    if entry.is_dir() {
+                        fs::remove_dir_all(&entry)?;
+                    } else {
+                        fs::remove_file(&entry)?;
+                    }
+                }
+            }
+
+            OnExisting::Resume => {
+                info!("resuming in output dir {:?}", self.path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// keeps the lock we just took fresh
+    fn locked(path: PathBuf) -> Self {
+        let lock_path = path.join(LOCK_FILE_NAME);
+
+        let (f, handle) = async move {
+            loop {
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                write_lock(&lock_path);
+            }
+        }
+        .remote_handle();
+        tokio::spawn(f);
+
+        Self {
+            path,
+            heartbeat_handle: Some(handle),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn close(mut self) -> Result<()> {
+        drop(self.heartbeat_handle.take());
+
+        let _ignore = fs::remove_file(self.path.join(LOCK_FILE_NAME));
+
+        Ok(())
+    }
+}
+
+/// creates the lock file, failing if another instance has a fresh one
+fn take_lock(dir: &Path) -> Result<()> {
+    let lock_path = dir.join(LOCK_FILE_NAME);
+
+    // a second try after clearing away a dead process's lock
+    for _ in 0..2 {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(mut file) => {
+                write!(file, "{}", process::id())?;
+                return Ok(());
+            }
+
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if is_locked(dir) {
+                    bail!("output dir {:?} is in use by another instance", dir);
+                }
+
+                debug!("removing stale lock file {:?}", lock_path);
+                match fs::remove_file(&lock_path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    bail!("output dir {:?} is in use by another instance", dir);
+}
+
+fn write_lock(lock_path: &Path) {
+    if let Err(e) = fs::write(lock_path, format!("{}", process::id())) {
+        warn!("couldn't write lock file {:?}: {}", lock_path, e);
+    }
+}
+
+fn is_locked(dir: &Path) -> bool {
+    fs::metadata(dir.join(LOCK_FILE_NAME))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map(|age| age < STALE_AFTER)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs::File};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "{}-test-{}-{}",
+            env!("CARGO_PKG_NAME"),
+            name,
+            process::id()
+        ));
+        let _ignore = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn locking() {
+        let dir = test_dir("locking");
+
+        let output_dir = OutputDir::persistent(dir.clone(), OnExisting::Refuse).unwrap();
+        assert!(dir.join(LOCK_FILE_NAME).exists());
+        assert!(OutputDir::persistent(dir.clone(), OnExisting::Resume).is_err());
+
+        output_dir.close().unwrap();
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+        OutputDir::persistent(dir.clone(), OnExisting::Refuse)
+            .unwrap()
+            .close()
+            .unwrap();
+
+        // left behind by an instance that died
+        File::create(dir.join(LOCK_FILE_NAME))
+            .unwrap()
+            .set_modified(SystemTime::now() - STALE_AFTER * 2)
+            .unwrap();
+        OutputDir::persistent(dir.clone(), OnExisting::Refuse)
+            .unwrap()
+            .close()
+            .unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn existing_contents() {
+        let dir = test_dir("existing");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("stream.mpd"), "old").unwrap();
+
+        // refusing doesn't leave the lock behind
+        assert!(OutputDir::persistent(dir.clone(), OnExisting::Refuse).is_err());
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+
+        let output_dir = OutputDir::persistent(dir.clone(), OnExisting::Resume).unwrap();
+        assert!(dir.join("stream.mpd").exists());
+        output_dir.close().unwrap();
+
+        let output_dir = OutputDir::persistent(dir.clone(), OnExisting::Clean).unwrap();
+        assert!(!dir.join("stream.mpd").exists());
+        assert!(!dir.join("nested").exists());
+        assert!(dir.join(LOCK_FILE_NAME).exists());
+        output_dir.close().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use crate::error::*;
+use bytes::Bytes;
+use log::*;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+pub const MANIFEST_NAME: &str = "stream.mpd";
+
+// media segments kept in memory, ffmpeg normally deletes them well before this
+// (window_size + extra_window_size per stream)
+const MAX_SEGMENTS: usize = 32;
+
+/// where ffmpeg's dash output ends up
+#[derive(Clone)]
+pub enum Segments {
+    Dir(PathBuf),
+    Memory(SegmentStore),
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct FileVersion {
+    pub modified: SystemTime,
+    pub len: u64,
+}
+
+impl Segments {
+    /// finished files and when they last changed
+    pub fn list(&self) -> Result<HashMap<String, FileVersion>> {
+        match self {
+            Segments::Dir(dir) => list_dir(dir),
+            Segments::Memory(store) => Ok(store.list()),
+        }
+    }
+
+    /// when one file last changed, without listing the others
+    pub fn version(&self, name: &str) -> Option<FileVersion> {
+        // names come straight from request paths
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return None;
+        }
+
+        match self {
+            Segments::Dir(dir) => {
+                let metadata = fs::metadata(dir.join(name)).ok()?;
+                Some(FileVersion {
+                    modified: metadata.modified().ok()?,
+                    len: metadata.len(),
+                })
+            }
+            Segments::Memory(store) => store.version(name),
+        }
+    }
+
+    pub async fn read(&self, name: &str) -> Result<Option<Bytes>> {
+        match self {
+            Segments::Dir(dir) => match tokio::fs::read(dir.join(name)).await {
+                Ok(data) => Ok(Some(data.into())),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Segments::Memory(store) => Ok(store.get(name)),
+        }
+    }
+
+    /// removes what ffmpeg wrote, once nobody is going to fetch it
+    pub fn clear(&self) {
+        match self {
+            Segments::Dir(dir) => {
+                let files = match list_dir(dir) {
+                    Ok(files) => files,
+                    Err(e) => {
+                        warn!("couldn't list {:?}: {}", dir, e);
+                        return;
+                    }
+                };
+
+                for name in files.keys().filter(|name| is_dash_file(name)) {
+                    if let Err(e) = fs::remove_file(dir.join(name)) {
+                        warn!("couldn't remove {}: {}", name, e);
+                    }
+                }
+            }
+            Segments::Memory(store) => store.clear(),
+        }
+    }
+}
+
+struct Entry {
+    data: Bytes,
+    modified: SystemTime,
+}
+
+#[derive(Default)]
+struct Inner {
+    files: HashMap<String, Entry>,
+    // oldest first
+    segment_order: VecDeque<String>,
+}
+
+/// manifests and segments that ffmpeg uploaded to us over http
+#[derive(Clone, Default)]
+pub struct SegmentStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SegmentStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Bytes> {
+        let inner = self.inner.lock().unwrap();
+        inner.files.get(name).map(|entry| entry.data.clone())
+    }
+
+    pub fn version(&self, name: &str) -> Option<FileVersion> {
+        let inner = self.inner.lock().unwrap();
+        inner.files.get(name).map(|entry| FileVersion {
+            modified: entry.modified,
+            len: entry.data.len() as u64,
+        })
+    }
+
+    pub fn put(&self, name: String, data: Bytes) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if is_media_segment(&name) && !inner.files.contains_key(&name) {
+            inner.segment_order.push_back(name.clone());
+
+            while inner.segment_order.len() > MAX_SEGMENTS {
+                if let Some(oldest) = inner.segment_order.pop_front() {
+                    debug!("store full, dropping {}", oldest);
+                    inner.files.remove(&oldest);
+                }
+            }
+        }
+
+        inner.files.insert(
+            name,
+            Entry {
+                data,
+                modified: SystemTime::now(),
+            },
+        );
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.segment_order.retain(|other| other != name);
+        inner.files.remove(name).is_some()
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.files.clear();
+        inner.segment_order.clear();
+    }
+
+    pub fn list(&self) -> HashMap<String, FileVersion> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .files
+            .iter()
+            .map(|(name, entry)| {
+                (
+                    name.clone(),
+                    FileVersion {
+                        modified: entry.modified,
+                        len: entry.data.len() as u64,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+fn is_media_segment(name: &str) -> bool {
+    !name.ends_with(".mpd") && !name.starts_with("init-")
+}
+
+/// leaves alone anything else that's in an output dir being resumed
+fn is_dash_file(name: &str) -> bool {
+    name == MANIFEST_NAME || name.starts_with("init-stream") || name.starts_with("chunk-stream")
+}
+
+fn list_dir(dir: &Path) -> Result<HashMap<String, FileVersion>> {
+    let mut files = HashMap::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        // ffmpeg writes into .tmp files and renames them when they're finished,
+        // and dot files are our own bookkeeping
+        if name.ends_with(".tmp") || name.starts_with('.') {
+            continue;
+        }
+
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+
+        files.insert(
+            name,
+            FileVersion {
+                modified: metadata.modified()?,
+                len: metadata.len(),
+            },
+        );
+    }
+
+    Ok(files)
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+        Some("mpd") => "application/dash+xml",
+        Some("webm") => "video/webm",
+        Some("mp4") | Some("m4s") => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eviction() {
+        let store = SegmentStore::new();
+        store.put(MANIFEST_NAME.to_string(), "manifest".into());
+        store.put("init-stream0.webm".to_string(), "init".into());
+
+        for n in 0..MAX_SEGMENTS + 2 {
+            store.put(format!("chunk-stream0-{:05}.webm", n), "chunk".into());
+        }
+
+        // only the oldest media segments go, never the manifest or init segments
+        let files = store.list();
+        assert_eq!(files.len(), MAX_SEGMENTS + 2);
+        assert!(store.get("chunk-stream0-00000.webm").is_none());
+        assert!(store.get("chunk-stream0-00001.webm").is_none());
+        assert!(store.get("chunk-stream0-00002.webm").is_some());
+        assert!(store.get(MANIFEST_NAME).is_some());
+        assert!(store.get("init-stream0.webm").is_some());
+
+        // rewriting a segment doesn't count it twice
+        store.put("chunk-stream0-00002.webm".to_string(), "again".into());
+        assert_eq!(store.list().len(), MAX_SEGMENTS + 2);
+        assert_eq!(store.get("chunk-stream0-00002.webm").unwrap(), "again");
+    }
+
+    #[test]
+    fn remove() {
+        let store = SegmentStore::new();
+        store.put("chunk-stream0-00000.webm".to_string(), "chunk".into());
+
+        assert!(store.remove("chunk-stream0-00000.webm"));
+        assert!(!store.remove("chunk-stream0-00000.webm"));
+        assert!(store.version("chunk-stream0-00000.webm").is_none());
+
+        // a removed segment no longer takes up a place in line
+        for n in 1..=MAX_SEGMENTS {
+            store.put(format!("chunk-stream0-{:05}.webm", n), "chunk".into());
+        }
+        assert!(store.get("chunk-stream0-00001.webm").is_some());
+    }
+
+    #[test]
+    fn content_types() {
+        assert_eq!(content_type("stream.mpd"), "application/dash+xml");
+        assert_eq!(content_type("chunk-stream0-00001.webm"), "video/webm");
+        assert_eq!(content_type("chunk-stream0-00001.m4s"), "video/mp4");
+        assert_eq!(content_type("init-stream0.mp4"), "video/mp4");
+        assert_eq!(content_type("notes.txt"), "application/octet-stream");
+        assert_eq!(content_type("stream"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,388 @@
+use crate::{
+    error::*,
+    ffmpeg::{Ffmpeg, FfmpegInput},
+    store::Segments,
+};
+use log::*;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
+
+// the wait before restarting ffmpeg after it failed, doubled for each recent failure
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+// failures longer ago than this don't count towards giving up
+const RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// changes to the encoder, each one restarts ffmpeg
+pub enum Command {
+    Start,
+    Stop,
+    Restart,
+    File {
+        path: PathBuf,
+        seek: Option<Duration>,
+    },
+    Seek(Duration),
+    Subtitles(Option<PathBuf>),
+    VideoBitrate(String),
+    /// ends the stream, the supervisor returns after this
+    End,
+}
+
+type Request = (Command, oneshot::Sender<Result<()>>);
+
+/// sends commands to a running supervisor
+#[derive(Clone)]
+pub struct Control {
+    sender: mpsc::UnboundedSender<Request>,
+}
+
+impl Control {
+    /// waits until the command was carried out
+    pub async fn send(&self, command: Command) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        if self.sender.send((command, reply)).is_err() {
+            bail!("the encoder isn't running anymore");
+        }
+
+        match response.await {
+            Ok(result) => result,
+            Err(_) => bail!("the encoder isn't running anymore"),
+        }
+    }
+}
+
+/// owns ffmpeg, restarting it with new settings when told to, and when it fails
+pub struct Supervisor {
+    label: String,
+    ffmpeg: Ffmpeg,
+    segments: Segments,
+    max_restarts: usize,
+    requests: mpsc::UnboundedReceiver<Request>,
+    // recent failures, oldest first
+    failures: VecDeque<Instant>,
+    restart_at: Option<Instant>,
+}
+
+impl Supervisor {
+    /// gives up once ffmpeg failed more than max_restarts times in 10 minutes
+    pub fn new(
+        label: String,
+        ffmpeg: Ffmpeg,
+        segments: Segments,
+        max_restarts: usize,
+    ) -> (Self, Control) {
+        let (sender, requests) = mpsc::unbounded_channel();
+
+        (
+            Self {
+                label,
+                ffmpeg,
+                segments,
+                max_restarts,
+                requests,
+                failures: VecDeque::new(),
+                restart_at: None,
+            },
+            Control { sender },
+        )
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// runs until ffmpeg exits on its own, fails too often, or the stream is ended
+    pub async fn run(mut self) -> Result<()> {
+        self.ffmpeg.start()?;
+
+        loop {
+            let (command, reply) = if self.ffmpeg.is_running() {
+                tokio::select! {
+                    result = self.ffmpeg.wait() => {
+                        if let Err(e) = result {
+                            self.failed(e)?;
+                        }
+                        if self.restart_at.is_none() {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    Some(request) = self.requests.recv() => request,
+                }
+            } else if let Some(restart_at) = self.restart_at {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(restart_at.into()) => {
+                        self.restart_at = None;
+                        info!("{}: restarting after failing", self.label);
+                        self.segments.clear();
+                        if let Err(e) = self.ffmpeg.start() {
+                            self.failed(e)?;
+                        }
+                        continue;
+                    }
+                    Some(request) = self.requests.recv() => request,
+                }
+            } else {
+                match self.requests.recv().await {
+                    Some(request) => request,
+                    // stopped, and nobody is left to start it again
+                    None => return Ok(()),
+                }
+            };
+
+            let end = matches!(command, Command::End);
+            let result = self.apply(command).await;
+            if let Err(e) = &result {
+                warn!("{}: {}", self.label, e);
+            }
+            let _ignore = reply.send(result);
+
+            if end {
+                return Ok(());
+            }
+        }
+    }
+
+    /// schedules a restart, unless ffmpeg has been failing too often
+    fn failed(&mut self, error: Error) -> Result<()> {
+        let now = Instant::now();
+        while let Some(failure) = self.failures.front() {
+            if now - *failure < RESTART_WINDOW {
+                break;
+            }
+            self.failures.pop_front();
+        }
+
+        error!("{}: {}", self.label, error);
+        if self.failures.len() >= self.max_restarts {
+            bail!(
+                "giving up after {} restarts in {} minutes",
+                self.failures.len(),
+                RESTART_WINDOW.as_secs() / 60
+            );
+        }
+        self.failures.push_back(now);
+
+        let delay = RESTART_DELAY
+            .saturating_mul(1 << (self.failures.len() - 1).min(16))
+            .min(MAX_RESTART_DELAY);
+        warn!("{}: restarting in {:?}", self.label, delay);
+        self.restart_at = Some(now + delay);
+
+        Ok(())
+    }
+
+    async fn apply(&mut self, command: Command) -> Result<()> {
+        let restart_pending = self.restart_at.is_some();
+
+        match command {
+            Command::Start => {
+                if self.ffmpeg.is_running() {
+                    bail!("already running");
+                }
+                info!("{}: starting", self.label);
+            }
+
+            Command::Stop => {
+                if !self.ffmpeg.is_running() && !restart_pending {
+                    bail!("already stopped");
+                }
+                info!("{}: stopping", self.label);
+                self.restart_at = None;
+                self.ffmpeg.stop().await;
+                self.segments.clear();
+                return Ok(());
+            }
+
+            Command::Restart => {
+                info!("{}: restarting", self.label);
+            }
+
+            // the segments stay until the viewers are done with them
+            Command::End => {
+                info!("{}: ending the stream", self.label);
+                self.ffmpeg.finish().await;
+                return Ok(());
+            }
+
+            setting => self.change(setting)?,
+        }
+
+        self.restart_at = None;
+        self.ffmpeg.stop().await;
+        // players start over with the new segments anyway
+        self.segments.clear();
+        self.ffmpeg.start()
+    }
+
+    /// checks a new setting and keeps it for the next start
+    fn change(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::File { path, seek } => {
+                self.file_input()?;
+                if !path.is_file() {
+                    bail!("{:?} isn't a file", path);
+                }
+                info!("{}: switching to {}", self.label, path.display());
+                self.ffmpeg.input = FfmpegInput::File { path, seek };
+            }
+
+            Command::Seek(time) => {
+                *self.file_input()? = Some(time);
+                info!("{}: seeking to {:?}", self.label, time);
+            }
+
+            Command::Subtitles(path) => {
+                self.file_input()?;
+                match &path {
+                    Some(path) => {
+                        if !path.is_file() {
+                            bail!("{:?} isn't a file", path);
+                        }
+                        info!("{}: subtitles from {}", self.label, path.display());
+                    }
+                    None => info!("{}: removing subtitles", self.label),
+                }
+                self.ffmpeg.subtitles_path = path;
+            }
+
+            Command::VideoBitrate(bitrate) => {
+                self.file_input()?;
+                check_bitrate(&bitrate)?;
+                info!("{}: video bitrate {}", self.label, bitrate);
+                self.ffmpeg.video_bitrate = bitrate;
+            }
+
+            Command::Start | Command::Stop | Command::Restart | Command::End => {}
+        }
+
+        Ok(())
+    }
+
+    /// restarting an rtmp input would drop whoever is publishing to it
+    fn file_input(&mut self) -> Result<&mut Option<Duration>> {
+        match &mut self.ffmpeg.input {
+            FfmpegInput::File { seek, .. } => Ok(seek),
+            FfmpegInput::Rtmp(_) => bail!("only possible when playing a file"),
+        }
+    }
+}
+
+/// like 2500k or 4M, so it can't add arguments to ffmpeg
+fn check_bitrate(bitrate: &str) -> Result<()> {
+    let number = bitrate.strip_suffix(['k', 'K', 'M']).unwrap_or(bitrate);
+    let valid = number.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && number.parse::<f64>().map(|n| n > 0.0).unwrap_or(false);
+    if !valid {
+        bail!("{:?} isn't a bitrate like 2500k", bitrate);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ffmpeg::FfmpegOutput, store::SegmentStore};
+    use std::path::Path;
+
+    fn supervisor(input: FfmpegInput) -> Supervisor {
+        let ffmpeg = Ffmpeg {
+            command: None,
+            verbose: false,
+            state: Default::default(),
+            input,
+            output: FfmpegOutput::Rtmp(([127, 0, 0, 1], 1935).into()),
+            cpu_used: 8,
+            framerate: 30,
+            crf: 30,
+            video_bitrate: "4000k".to_string(),
+            video_resolution: "1280x720".to_string(),
+            audio_bitrate: "128k".to_string(),
+            audio_sample_rate: "44100".to_string(),
+            subtitles_path: None,
+            utc_timing_url: "time".to_string(),
+        };
+        let segments = Segments::Memory(SegmentStore::new());
+        Supervisor::new("test".to_string(), ffmpeg, segments, 0).0
+    }
+
+    #[test]
+    fn changes() {
+        let file = PathBuf::from("Cargo.toml");
+        let mut supervisor = supervisor(FfmpegInput::File {
+            path: file.clone(),
+            seek: None,
+        });
+
+        supervisor
+            .change(Command::VideoBitrate("2500k".to_string()))
+            .unwrap();
+        assert_eq!(supervisor.ffmpeg.video_bitrate, "2500k");
+        assert!(supervisor
+            .change(Command::VideoBitrate("2500k -y".to_string()))
+            .is_err());
+        assert_eq!(supervisor.ffmpeg.video_bitrate, "2500k");
+
+        let minute = Duration::from_secs(60);
+        supervisor.change(Command::Seek(minute)).unwrap();
+        assert_eq!(*supervisor.file_input().unwrap(), Some(minute));
+
+        supervisor
+            .change(Command::Subtitles(Some(file.clone())))
+            .unwrap();
+        assert_eq!(supervisor.ffmpeg.subtitles_path, Some(file.clone()));
+        assert!(supervisor
+            .change(Command::Subtitles(Some("missing.srt".into())))
+            .is_err());
+        supervisor.change(Command::Subtitles(None)).unwrap();
+        assert_eq!(supervisor.ffmpeg.subtitles_path, None);
+
+        supervisor
+            .change(Command::File {
+                path: "build.rs".into(),
+                seek: None,
+            })
+            .unwrap();
+        assert!(matches!(
+            &supervisor.ffmpeg.input,
+            FfmpegInput::File { path, seek: None } if path == Path::new("build.rs")
+        ));
+        assert!(supervisor
+            .change(Command::File {
+                path: "missing.mkv".into(),
+                seek: None,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn rtmp_changes() {
+        let mut supervisor = supervisor(FfmpegInput::Rtmp(([127, 0, 0, 1], 1935).into()));
+
+        assert!(supervisor
+            .change(Command::Seek(Duration::from_secs(60)))
+            .is_err());
+        assert!(supervisor
+            .change(Command::VideoBitrate("2500k".to_string()))
+            .is_err());
+        assert_eq!(supervisor.ffmpeg.video_bitrate, "4000k");
+    }
+
+    #[test]
+    fn bitrates() {
+        for bitrate in &["2500k", "4M", "1.5M", "800K", "128000"] {
+            assert!(check_bitrate(bitrate).is_ok(), "{}", bitrate);
+        }
+        for bitrate in &[
+            "", "k", "0k", "-1k", "2500k -y", "1e3k", "nan", "inf", "1..5M",
+        ] {
+            assert!(check_bitrate(bitrate).is_err(), "{}", bitrate);
+        }
+    }
+}
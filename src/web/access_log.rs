@@ -0,0 +1,257 @@
+use super::client::{self, TrustedProxies, ViewerId};
+use crate::{error::*, helpers::*};
+use futures::{future::RemoteHandle, FutureExt};
+use log::*;
+use std::{
+    fs::OpenOptions,
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime},
+};
+use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc};
+use warp::{
+    filters::path::FullPath,
+    http::{Method, Version},
+    Filter, Rejection,
+};
+
+pub struct RequestInfo {
+    start: Instant,
+    client_ip: Option<IpAddr>,
+    session: Option<String>,
+    method: Method,
+    path: FullPath,
+    version: Option<Version>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+/// collects what we want to log before the request is handled
+pub fn request_info(
+    proxies: &TrustedProxies,
+) -> impl Filter<Extract = (RequestInfo,), Error = Rejection> + Clone {
+    warp::any()
+        .map(Instant::now)
+        .and(proxies.client_ip())
+        .and(client::viewer_session())
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(client::http_version())
+        .and(warp::header::optional::<String>("referer"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(
+            |start, client_ip, session, method, path, version, referer, user_agent| RequestInfo {
+                start,
+                client_ip,
+                session,
+                method,
+                path,
+                version,
+                referer,
+                user_agent,
+            },
+        )
+}
+
+impl RequestInfo {
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    /// same as how the files route tracks viewers
+    pub fn viewer_id(&self) -> Option<ViewerId> {
+        match (&self.session, self.client_ip) {
+            (Some(session), _) => Some(ViewerId::Session(session.clone())),
+            (None, Some(ip)) => Some(ViewerId::Ip(ip)),
+            (None, None) => None,
+        }
+    }
+}
+
+// lines waiting for the file, past this they're dropped instead of piling up in memory
+const QUEUED_LINES: usize = 1024;
+
+#[derive(Clone)]
+pub struct AccessLog {
+    console: bool,
+    // lines for the file, written by a task so requests never wait on the disk
+    file: Option<mpsc::Sender<String>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    pub fn start(
+        console: bool,
+        file_path: Option<&Path>,
+    ) -> Result<(Self, Option<RemoteHandle<()>>)> {
+        let file_path = match file_path {
+            Some(file_path) => file_path,
+            None => {
+                return Ok((
+                    Self {
+                        console,
+                        file: None,
+                        dropped: Default::default(),
+                    },
+                    None,
+                ))
+            }
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path)
+            .chain_err(|| format!("couldn't open access log {:?}", file_path))?;
+        let mut file = File::from_std(file);
+
+        let dropped: Arc<AtomicU64> = Default::default();
+        let (sender, mut lines) = mpsc::channel::<String>(QUEUED_LINES);
+        let (f, handle) = {
+            let dropped = dropped.clone();
+            async move {
+                while let Some(line) = lines.recv().await {
+                    let count = dropped.swap(0, Ordering::SeqCst);
+                    if count > 0 {
+                        warn!(
+                            "access log: dropped {} lines, the file can't keep up",
+                            count
+                        );
+                    }
+
+                    let written = async {
+                        file.write_all(line.as_bytes()).await?;
+                        file.flush().await
+                    };
+                    if let Err(e) = written.await {
+                        warn!("access log: {}", e);
+                    }
+                }
+            }
+        }
+        .remote_handle();
+        tokio::spawn(f);
+
+        Ok((
+            Self {
+                console,
+                file: Some(sender),
+                dropped,
+            },
+            Some(handle),
+        ))
+    }
+
+    /// called once the body is done, `bytes` is what was actually sent
+    pub fn log(&self, info: &RequestInfo, status: u16, bytes: u64, complete: bool) {
+        if !self.console && self.file.is_none() {
+            return;
+        }
+
+        let duration = info.start.elapsed();
+        let client = info
+            .client_ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let user_agent = info.user_agent.as_deref().unwrap_or("-");
+
+        if self.console {
+            debug!(
+                "client={} method={} path={} status={} bytes={} complete={} duration={:.1?} \
+                 user_agent={:?}",
+                client,
+                info.method,
+                info.path.as_str(),
+                status,
+                bytes,
+                complete,
+                duration,
+                user_agent,
+            );
+        }
+
+        if let Some(file) = &self.file {
+            let line = combined_line(info, status, bytes, SystemTime::now() - duration);
+            if file.try_send(line).is_err() {
+                self.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// one line in combined log format, `time` being when the request came in
+fn combined_line(info: &RequestInfo, status: u16, bytes: u64, time: SystemTime) -> String {
+    // warp's tls server doesn't say which version was used, so leave it out instead of guessing
+    let request = match info.version {
+        Some(version) => format!("{} {} {:?}", info.method, info.path.as_str(), version),
+        None => format!("{} {}", info.method, info.path.as_str()),
+    };
+
+    format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"\n",
+        info.client_ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        UtcTime::from_system_time(time).to_clf(),
+        escape_quotes(&request),
+        status,
+        bytes,
+        escape_quotes(info.referer.as_deref().unwrap_or("-")),
+        escape_quotes(info.user_agent.as_deref().unwrap_or("-")),
+    )
+}
+
+fn escape_quotes(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    async fn request() -> RequestInfo {
+        warp::test::request()
+            .path("/live/a?token=x")
+            .remote_addr("1.2.3.4:1234".parse().unwrap())
+            .header("user-agent", "curl \"quoted\"")
+            .filter(&request_info(&TrustedProxies::new(Vec::new())))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn combined_log_format() {
+        let mut info = request().await;
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+
+        // like behind warp's tls server
+        assert_eq!(
+            combined_line(&info, 200, 5, time),
+            "1.2.3.4 - - [29/Feb/2000:00:00:00 +0000] \"GET /live/a\" 200 5 \"-\" \
+             \"curl \\\"quoted\\\"\"\n"
+        );
+
+        info.version = Some(Version::HTTP_2);
+        assert!(combined_line(&info, 200, 5, time).contains("\"GET /live/a HTTP/2.0\""));
+    }
+
+    #[tokio::test]
+    async fn full_queue() {
+        let (sender, _lines) = mpsc::channel(1);
+        let access_log = AccessLog {
+            console: false,
+            file: Some(sender),
+            dropped: Default::default(),
+        };
+
+        let info = request().await;
+        access_log.log(&info, 200, 5, true);
+        access_log.log(&info, 200, 5, true);
+        assert_eq!(access_log.dropped.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,531 @@
+use super::{
+    limits::{Attempt, Limited, Limits},
+    stream::Stream,
+};
+use crate::{error::*, helpers::*, supervisor::Command};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use warp::{
+    filters::BoxedFilter,
+    http::{
+        header::{SET_COOKIE, WWW_AUTHENTICATE},
+        HeaderValue, StatusCode,
+    },
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+const DASHBOARD: &str = include_str!("admin.html");
+
+const COOKIE_NAME: &str = "dash_admin";
+
+const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    stream: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FileBody {
+    path: PathBuf,
+    seek: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SeekBody {
+    time: String,
+}
+
+#[derive(Deserialize)]
+struct SubtitlesBody {
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct BitrateBody {
+    video_bitrate: String,
+}
+
+#[derive(Deserialize)]
+struct KickBody {
+    viewer: String,
+}
+
+#[derive(Serialize)]
+struct StreamStatus {
+    name: Option<String>,
+    path: String,
+    live: bool,
+    #[serde(flatten)]
+    status: super::status::Status,
+}
+
+fn command(action: &str, body: &[u8]) -> Result<Command> {
+    Ok(match action {
+        "start" => Command::Start,
+        "stop" => Command::Stop,
+        "restart" => Command::Restart,
+        "file" => {
+            let FileBody { path, seek } = parse(body)?;
+            Command::File {
+                path,
+                seek: seek.as_deref().map(parse_duration).transpose()?,
+            }
+        }
+        "seek" => {
+            let SeekBody { time } = parse(body)?;
+            Command::Seek(parse_duration(&time)?)
+        }
+        "subtitles" => {
+            let SubtitlesBody { path } = parse(body)?;
+            Command::Subtitles(path)
+        }
+        "bitrate" => {
+            let BitrateBody { video_bitrate } = parse(body)?;
+            Command::VideoBitrate(video_bitrate)
+        }
+        _ => bail!("no action {:?}", action),
+    })
+}
+
+fn parse<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T> {
+    serde_json::from_slice(body).chain_err(|| "bad request body")
+}
+
+fn error_response(status: StatusCode, message: String) -> Response {
+    warp::reply::with_status(message, status).into_response()
+}
+
+struct Inner {
+    password: Option<String>,
+    // signs session cookies, so logins end when we restart
+    key: Vec<u8>,
+    streams: Arc<Vec<Stream>>,
+    limits: Limits,
+}
+
+/// the dashboard and control api, for whoever knows the admin password
+#[derive(Clone)]
+pub struct Admin {
+    inner: Arc<Inner>,
+}
+
+impl Admin {
+    pub fn new(password: Option<String>, streams: Arc<Vec<Stream>>, limits: Limits) -> Self {
+        let mut key = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+
+        Self {
+            inner: Arc::new(Inner {
+                password,
+                key,
+                streams,
+                limits,
+            }),
+        }
+    }
+
+    fn signature(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.inner.key).expect("any key size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// `expires.signature`
+    fn new_session(&self) -> String {
+        let expires = (SystemTime::now() + SESSION_LIFETIME)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+        let signature = hex::encode(self.signature(&expires).finalize().into_bytes());
+
+        format!("{}.{}", expires, signature)
+    }
+
+    fn verify_session(&self, token: &str) -> bool {
+        let check = || {
+            let (expires, signature) = token.split_once('.')?;
+            self.signature(expires)
+                .verify_slice(&hex::decode(signature).ok()?)
+                .ok()?;
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            expires.parse::<u64>().ok().filter(|expires| *expires > now)
+        };
+
+        check().is_some()
+    }
+
+    fn has_session(&self, cookie: Option<&str>) -> bool {
+        self.inner.password.is_some()
+            && cookie
+                .map(|token| self.verify_session(token))
+                .unwrap_or(false)
+    }
+
+    fn check_password(&self, given: &str, attempt: &Attempt) -> bool {
+        let password = match &self.inner.password {
+            Some(password) => password,
+            None => return false,
+        };
+
+        if !attempt.allowed() {
+            return false;
+        }
+        let matches = secrets_match(given, password);
+        if !matches {
+            attempt.failed();
+        }
+        matches
+    }
+
+    /// the password as a bearer token or with basic auth and any user name, or a session cookie
+    fn is_admin(
+        &self,
+        authorization: Option<&str>,
+        cookie: Option<&str>,
+        attempt: &Attempt,
+    ) -> bool {
+        if self.has_session(cookie) {
+            return true;
+        }
+
+        let authorization = match authorization {
+            Some(authorization) => authorization,
+            None => return false,
+        };
+
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return self.check_password(token.trim(), attempt);
+        }
+
+        authorization
+            .strip_prefix("Basic ")
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|credentials| {
+                let (_user, given) = credentials.split_once(':')?;
+                Some(self.check_password(given, attempt))
+            })
+            .unwrap_or(false)
+    }
+
+    /// passes admins, so the preview on the dashboard gets past viewer auth
+    pub fn session(&self) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        let admin = self.clone();
+
+        warp::cookie::optional::<String>(COOKIE_NAME)
+            .and_then(move |cookie: Option<String>| {
+                let result = if admin.has_session(cookie.as_deref()) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                };
+                async move { result }
+            })
+            .untuple_one()
+    }
+
+    /// the named stream, which can be left out when there's only one
+    fn find_stream(&self, name: Option<&str>) -> Option<&Stream> {
+        let streams = &self.inner.streams;
+        match name {
+            Some(name) => streams
+                .iter()
+                .find(|stream| stream.name.as_deref() == Some(name)),
+            None if streams.len() == 1 => streams.first(),
+            None => None,
+        }
+    }
+
+    fn status(&self) -> Vec<StreamStatus> {
+        self.inner
+            .streams
+            .iter()
+            .map(|stream| StreamStatus {
+                name: stream.name.clone(),
+                path: stream.path(),
+                live: stream.is_live(),
+                status: stream.status(),
+            })
+            .collect()
+    }
+
+    async fn handle(&self, action: &str, query: StreamQuery, body: &[u8]) -> Response {
+        let stream = match self.find_stream(query.stream.as_deref()) {
+            Some(stream) => stream,
+            None => return error_response(StatusCode::NOT_FOUND, "no such stream".to_string()),
+        };
+
+        if action == "kick" {
+            return match parse::<KickBody>(body) {
+                Ok(KickBody { viewer }) if stream.kick(&viewer) => {
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                Ok(_) => error_response(StatusCode::NOT_FOUND, "no such viewer".to_string()),
+                Err(e) => error_response(StatusCode::BAD_REQUEST, e.to_string()),
+            };
+        }
+
+        let command = match command(action, body) {
+            Ok(command) => command,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+
+        match stream.control.send(command).await {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(e) => error_response(StatusCode::CONFLICT, e.to_string()),
+        }
+    }
+
+    /// /admin, POST /api/admin/login, GET /api/admin/status
+    /// and POST /api/admin/<action>?stream=<name>
+    pub fn routes(&self) -> BoxedFilter<(Response,)> {
+        if self.inner.password.is_none() {
+            return warp::any()
+                .and_then(|| async { Err::<Response, _>(warp::reject::not_found()) })
+                .boxed();
+        }
+
+        // logs in with a login form on the dashboard itself
+        let dashboard = warp::path!("admin")
+            .and(warp::get())
+            .map(|| warp::reply::html(DASHBOARD).into_response());
+
+        let login = {
+            let admin = self.clone();
+            warp::path!("api" / "admin" / "login")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(4 * 1024))
+                .and(warp::body::form::<HashMap<String, String>>())
+                .and(self.inner.limits.attempt())
+                .map(move |form: HashMap<String, String>, attempt: Attempt| {
+                    if !attempt.allowed() {
+                        return Limited::TooManyRequests.response();
+                    }
+                    let given = form.get("password").map(String::as_str).unwrap_or_default();
+                    if !admin.check_password(given, &attempt) {
+                        return StatusCode::UNAUTHORIZED.into_response();
+                    }
+
+                    let mut response = StatusCode::NO_CONTENT.into_response();
+                    response.headers_mut().insert(
+                        SET_COOKIE,
+                        HeaderValue::from_str(&format!(
+                            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+                            COOKIE_NAME,
+                            admin.new_session(),
+                            SESSION_LIFETIME.as_secs()
+                        ))
+                        .expect("tokens are ascii"),
+                    );
+                    response
+                })
+        };
+
+        let authorized = {
+            let admin = self.clone();
+            warp::header::optional::<String>("authorization")
+                .and(warp::cookie::optional::<String>(COOKIE_NAME))
+                .and(self.inner.limits.attempt())
+                .and_then(
+                    move |authorization: Option<String>,
+                          cookie: Option<String>,
+                          attempt: Attempt| {
+                        let authorized =
+                            admin.is_admin(authorization.as_deref(), cookie.as_deref(), &attempt);
+                        async move {
+                            if authorized {
+                                Ok(())
+                            } else {
+                                Err(warp::reject::custom(AdminUnauthorized))
+                            }
+                        }
+                    },
+                )
+                .untuple_one()
+        };
+
+        let status = {
+            let admin = self.clone();
+            warp::path!("api" / "admin" / "status")
+                .and(warp::get())
+                .and(authorized.clone())
+                .map(move || warp::reply::json(&admin.status()).into_response())
+        };
+
+        let action = {
+            let admin = self.clone();
+            warp::path!("api" / "admin" / String)
+                .and(warp::post())
+                .and(authorized)
+                .and(warp::query::<StreamQuery>())
+                .and(
+                    warp::body::content_length_limit(16 * 1024)
+                        .and(warp::body::bytes())
+                        // start, stop and restart don't need a body
+                        .or(warp::any().map(Bytes::new))
+                        .unify(),
+                )
+                .and_then(move |action: String, query: StreamQuery, body: Bytes| {
+                    let admin = admin.clone();
+                    async move { Ok::<_, Rejection>(admin.handle(&action, query, &body).await) }
+                })
+        };
+
+        dashboard
+            .or(login)
+            .unify()
+            .or(status)
+            .unify()
+            .or(action)
+            .unify()
+            .boxed()
+    }
+}
+
+#[derive(Debug)]
+pub struct AdminUnauthorized;
+
+impl warp::reject::Reject for AdminUnauthorized {}
+
+impl AdminUnauthorized {
+    pub fn response(&self) -> Response {
+        let mut response = StatusCode::UNAUTHORIZED.into_response();
+        response.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"dash-live-stream admin\""),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{client::TrustedProxies, limits::LimitOptions},
+        *,
+    };
+    use futures::future::RemoteHandle;
+    use std::path::Path;
+
+    fn start_admin() -> (Admin, Limits, RemoteHandle<()>) {
+        let (limits, handle) = Limits::start(
+            LimitOptions {
+                max_viewers: None,
+                max_viewers_per_address: 1,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                rate_limit: None,
+            },
+            TrustedProxies::new(Vec::new()),
+        );
+        let admin = Admin::new(
+            Some("hunter2".to_string()),
+            Arc::new(Vec::new()),
+            limits.clone(),
+        );
+        (admin, limits, handle)
+    }
+
+    async fn attempt(limits: &Limits) -> Attempt {
+        warp::test::request()
+            .remote_addr("1.2.3.4:1234".parse().unwrap())
+            .filter(&limits.attempt())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sessions() {
+        let (admin, _limits, _handle) = start_admin();
+
+        let session = admin.new_session();
+        assert!(admin.verify_session(&session));
+        assert!(admin.has_session(Some(&session)));
+
+        let (expires, signature) = session.split_once('.').unwrap();
+        let later: u64 = expires.parse::<u64>().unwrap() + 1;
+        assert!(!admin.verify_session(&format!("{}.{}", later, signature)));
+        assert!(!admin.verify_session(expires));
+        assert!(!admin.has_session(None));
+
+        // signed, but expired
+        let past = "1000";
+        let signature = hex::encode(admin.signature(past).finalize().into_bytes());
+        assert!(!admin.verify_session(&format!("{}.{}", past, signature)));
+
+        // from another run
+        let (other, _limits, _handle) = start_admin();
+        assert!(!other.verify_session(&session));
+    }
+
+    #[tokio::test]
+    async fn passwords() {
+        let (admin, limits, _handle) = start_admin();
+        let attempt = attempt(&limits).await;
+
+        assert!(admin.is_admin(Some("Bearer hunter2"), None, &attempt));
+        let basic = format!("Basic {}", STANDARD.encode("anyone:hunter2"));
+        assert!(admin.is_admin(Some(&basic), None, &attempt));
+
+        assert!(!admin.is_admin(Some("Bearer hunter3"), None, &attempt));
+        assert!(!admin.is_admin(Some("hunter2"), None, &attempt));
+        assert!(!admin.is_admin(None, None, &attempt));
+
+        while attempt.allowed() {
+            attempt.failed();
+        }
+        assert!(!admin.is_admin(Some("Bearer hunter2"), None, &attempt));
+        // logged in admins aren't affected
+        assert!(admin.is_admin(None, Some(&admin.new_session()), &attempt));
+    }
+
+    #[test]
+    fn commands() {
+        assert!(matches!(command("stop", b""), Ok(Command::Stop)));
+        assert!(matches!(
+            command("seek", br#"{"time": "1:30"}"#),
+            Ok(Command::Seek(time)) if time == Duration::from_secs(90)
+        ));
+        assert!(command("seek", br#"{"time": "soon"}"#).is_err());
+        assert!(matches!(
+            command("bitrate", br#"{"video_bitrate": "2500k"}"#),
+            Ok(Command::VideoBitrate(bitrate)) if bitrate == "2500k"
+        ));
+        assert!(command("bitrate", br#"{"bitrate": "2500k"}"#).is_err());
+        assert!(matches!(
+            command("file", br#"{"path": "movie.mkv", "seek": "10"}"#),
+            Ok(Command::File { path, seek: Some(seek) })
+                if path == Path::new("movie.mkv") && seek == Duration::from_secs(10)
+        ));
+        assert!(matches!(
+            command("file", br#"{"path": "movie.mkv"}"#),
+            Ok(Command::File { seek: None, .. })
+        ));
+        assert!(matches!(
+            command("subtitles", br#"{"path": "movie.srt"}"#),
+            Ok(Command::Subtitles(Some(path))) if path == Path::new("movie.srt")
+        ));
+        assert!(matches!(
+            command("subtitles", br#"{"path": null}"#),
+            Ok(Command::Subtitles(None))
+        ));
+        assert!(command("explode", b"").is_err());
+    }
+}
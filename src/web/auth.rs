@@ -0,0 +1,528 @@
+use super::limits::{Attempt, Limited, Limits};
+use crate::{error::*, helpers::*};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{future::RemoteHandle, FutureExt};
+use hmac::{Hmac, Mac};
+use log::*;
+use rand::RngCore;
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use warp::{
+    http::{
+        header::{SET_COOKIE, WWW_AUTHENTICATE},
+        HeaderValue, StatusCode,
+    },
+    reject::Reject,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+const LOGIN: &str = include_str!("login.html");
+
+const COOKIE_NAME: &str = "dash_token";
+
+// viewer name in tokens handed out by the password page
+const PASSWORD_VIEWER: &str = "-";
+
+const VIEWERS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct AuthOptions {
+    pub basic_auth: Option<(String, String)>,
+    pub password: Option<String>,
+    pub link_secret: Option<String>,
+    pub viewers_file: Option<PathBuf>,
+    pub link_lifetime: Duration,
+}
+
+#[derive(Debug)]
+pub struct Unauthorized {
+    login_page: bool,
+    basic: bool,
+}
+
+impl Reject for Unauthorized {}
+
+impl Unauthorized {
+    pub fn response(&self) -> Response {
+        let mut response = if self.login_page {
+            warp::reply::html(LOGIN).into_response()
+        } else {
+            Response::default()
+        };
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+
+        if self.basic && !self.login_page {
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"dash-live-stream\""),
+            );
+        }
+
+        response
+    }
+}
+
+struct Inner {
+    basic_auth: Option<(String, String)>,
+    password: Option<String>,
+    key: Vec<u8>,
+    // invited viewers and when they were added, None if we don't hand out links
+    viewers: Option<Mutex<HashMap<String, u64>>>,
+    link_lifetime: Duration,
+    limits: Limits,
+}
+
+/// basic auth, the password page and signed viewer links
+#[derive(Clone)]
+pub struct Auth {
+    inner: Arc<Inner>,
+}
+
+impl Auth {
+    pub fn start(
+        options: AuthOptions,
+        base_url: String,
+        limits: Limits,
+    ) -> Result<(Self, Option<RemoteHandle<()>>)> {
+        let AuthOptions {
+            basic_auth,
+            password,
+            link_secret,
+            viewers_file,
+            link_lifetime,
+        } = options;
+
+        let key = match link_secret {
+            Some(link_secret) => link_secret.into_bytes(),
+            None => {
+                if viewers_file.is_some() {
+                    info!("viewer links will stop working on restart unless --link-secret is set");
+                }
+
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+
+        // links from before a restart are only good with the same --link-secret anyway
+        let viewers = match &viewers_file {
+            Some(path) => Some(Mutex::new(
+                read_viewers(path)?
+                    .into_iter()
+                    .map(|name| (name, 0))
+                    .collect(),
+            )),
+            None => None,
+        };
+
+        let auth = Self {
+            inner: Arc::new(Inner {
+                basic_auth,
+                password,
+                key,
+                viewers,
+                link_lifetime,
+                limits,
+            }),
+        };
+
+        let handle = viewers_file.map(|path| {
+            let auth = auth.clone();
+
+            let (f, handle) = async move {
+                let mut last_modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                auth.log_links(&base_url, &HashMap::new());
+
+                loop {
+                    tokio::time::sleep(VIEWERS_POLL_INTERVAL).await;
+
+                    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                    if modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+
+                    // a missing file revokes everyone
+                    let viewers = read_viewers(&path).unwrap_or_else(|e| {
+                        warn!("viewers file: {}", e);
+                        HashSet::new()
+                    });
+
+                    let previous = {
+                        let mut current = auth.viewers().unwrap().lock().unwrap();
+                        for name in current.keys().filter(|name| !viewers.contains(*name)) {
+                            info!("revoked link for {}", name);
+                        }
+
+                        // someone added back doesn't get their old links back
+                        let now = unix_time();
+                        let viewers = viewers
+                            .into_iter()
+                            .map(|name| {
+                                let added = current.get(&name).copied().unwrap_or(now);
+                                (name, added)
+                            })
+                            .collect();
+                        std::mem::replace(&mut *current, viewers)
+                    };
+                    auth.log_links(&base_url, &previous);
+                }
+            }
+            .remote_handle();
+            tokio::spawn(f);
+
+            handle
+        });
+
+        Ok((auth, handle))
+    }
+
+    fn viewers(&self) -> Option<&Mutex<HashMap<String, u64>>> {
+        self.inner.viewers.as_ref()
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.inner.basic_auth.is_some() || self.inner.password.is_some() || self.viewers().is_some()
+    }
+
+    /// prints a fresh link for everyone that isn't in `already_logged`
+    fn log_links(&self, base_url: &str, already_logged: &HashMap<String, u64>) {
+        let viewers = match self.viewers() {
+            Some(viewers) => viewers.lock().unwrap().clone(),
+            None => return,
+        };
+
+        let mut names: Vec<_> = viewers
+            .keys()
+            .filter(|name| !already_logged.contains_key(*name))
+            .collect();
+        names.sort();
+        for name in names {
+            info!(
+                "link for {}: {}?token={}",
+                name,
+                base_url,
+                self.sign(name, self.inner.link_lifetime)
+            );
+        }
+    }
+
+    fn signature(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.inner.key).expect("any key size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// `viewer.issued.expires.signature`
+    fn sign(&self, viewer: &str, lifetime: Duration) -> String {
+        let issued = unix_time();
+        let expires = issued + lifetime.as_secs();
+        let payload = format!("{}.{}.{}", viewer, issued, expires);
+        let signature = hex::encode(self.signature(&payload).finalize().into_bytes());
+
+        format!("{}.{}", payload, signature)
+    }
+
+    /// seconds left if the token is still good
+    fn verify(&self, token: &str) -> Option<u64> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        let (viewer, times) = payload.split_once('.')?;
+        let (issued, expires) = times.split_once('.')?;
+
+        self.signature(payload)
+            .verify_slice(&hex::decode(signature).ok()?)
+            .ok()?;
+
+        let issued = issued.parse::<u64>().ok()?;
+        let remaining = expires
+            .parse::<u64>()
+            .ok()?
+            .checked_sub(unix_time())
+            .filter(|remaining| *remaining > 0)?;
+
+        let allowed = if viewer == PASSWORD_VIEWER {
+            self.inner.password.is_some()
+        } else {
+            self.viewers()
+                .and_then(|viewers| viewers.lock().unwrap().get(viewer).copied())
+                .map(|added| issued >= added)
+                .unwrap_or(false)
+        };
+
+        if allowed {
+            Some(remaining)
+        } else {
+            None
+        }
+    }
+
+    fn check_basic(&self, authorization: Option<&str>, attempt: &Attempt) -> bool {
+        let (user, password) = match &self.inner.basic_auth {
+            Some(basic_auth) => basic_auth,
+            None => return false,
+        };
+
+        let credentials = match authorization.and_then(|value| value.strip_prefix("Basic ")) {
+            Some(encoded) => STANDARD
+                .decode(encoded.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok()),
+            None => return false,
+        };
+        if !attempt.allowed() {
+            return false;
+        }
+
+        let matches = match credentials.as_deref().and_then(|s| s.split_once(':')) {
+            Some((given_user, given_password)) => {
+                // both, so the time doesn't tell which one was wrong
+                secrets_match(given_user, user) & secrets_match(given_password, password)
+            }
+            None => false,
+        };
+        if !matches {
+            attempt.failed();
+        }
+        matches
+    }
+
+    fn check(
+        &self,
+        query: &str,
+        cookie: Option<&str>,
+        authorization: Option<&str>,
+        accept: Option<&str>,
+        attempt: &Attempt,
+    ) -> std::result::Result<Option<HeaderValue>, Rejection> {
+        if !self.is_enabled() || self.check_basic(authorization, attempt) {
+            return Ok(None);
+        }
+
+        // a link was opened, remember it so the manifest and segments get it too
+        let query_token = url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "token")
+            .map(|(_, value)| value.into_owned());
+        if let Some(token) = query_token {
+            if let Some(remaining) = self.verify(&token) {
+                return Ok(Some(cookie_header(&token, remaining)));
+            }
+        }
+
+        if cookie.and_then(|token| self.verify(token)).is_some() {
+            return Ok(None);
+        }
+
+        Err(warp::reject::custom(Unauthorized {
+            login_page: self.inner.password.is_some()
+                && accept
+                    .map(|accept| accept.contains("text/html"))
+                    .unwrap_or(false),
+            basic: self.inner.basic_auth.is_some(),
+        }))
+    }
+
+    /// passes requests that are allowed in, extracting a cookie to set if they used a link
+    pub fn filter(
+        &self,
+    ) -> impl Filter<Extract = (Option<HeaderValue>,), Error = Rejection> + Clone {
+        let auth = self.clone();
+
+        warp::query::raw()
+            .or(warp::any().map(String::new))
+            .unify()
+            .and(warp::cookie::optional::<String>(COOKIE_NAME))
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::header::optional::<String>("accept"))
+            .and(self.inner.limits.attempt())
+            .and_then(
+                move |query: String,
+                      cookie: Option<String>,
+                      authorization: Option<String>,
+                      accept: Option<String>,
+                      attempt: Attempt| {
+                    let result = auth.check(
+                        &query,
+                        cookie.as_deref(),
+                        authorization.as_deref(),
+                        accept.as_deref(),
+                        &attempt,
+                    );
+                    async move { result }
+                },
+            )
+    }
+
+    /// POST /login from the password page
+    pub fn login_route(&self) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+        let auth = self.clone();
+
+        warp::path!("login")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(4 * 1024))
+            .and(warp::body::form::<HashMap<String, String>>())
+            .and(self.inner.limits.attempt())
+            .map(move |form: HashMap<String, String>, attempt: Attempt| {
+                let password = match &auth.inner.password {
+                    Some(password) => password,
+                    None => return StatusCode::NOT_FOUND.into_response(),
+                };
+
+                if !attempt.allowed() {
+                    return Limited::TooManyRequests.response();
+                }
+                let given = form.get("password").map(String::as_str).unwrap_or_default();
+                if !secrets_match(given, password) {
+                    attempt.failed();
+                    return StatusCode::UNAUTHORIZED.into_response();
+                }
+
+                let token = auth.sign(PASSWORD_VIEWER, auth.inner.link_lifetime);
+                let mut response = StatusCode::NO_CONTENT.into_response();
+                response.headers_mut().insert(
+                    SET_COOKIE,
+                    cookie_header(&token, auth.inner.link_lifetime.as_secs()),
+                );
+                response
+            })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn cookie_header(token: &str, max_age: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+        COOKIE_NAME, token, max_age
+    ))
+    .expect("tokens are ascii")
+}
+
+/// one viewer name per line, # for comments
+fn read_viewers(path: &PathBuf) -> Result<HashSet<String>> {
+    let text =
+        fs::read_to_string(path).chain_err(|| format!("couldn't read viewers file {:?}", path))?;
+
+    let mut viewers = HashSet::new();
+    for line in text.lines() {
+        let name = line.split('#').next().unwrap_or_default().trim();
+        if name.is_empty() {
+            continue;
+        }
+
+        if name == PASSWORD_VIEWER
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            warn!(
+                "viewers file: skipping {:?}, use letters, numbers, _ or -",
+                name
+            );
+            continue;
+        }
+
+        viewers.insert(name.to_string());
+    }
+
+    Ok(viewers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{client::TrustedProxies, limits::LimitOptions},
+        *,
+    };
+
+    fn auth(limits: Limits) -> Auth {
+        Auth {
+            inner: Arc::new(Inner {
+                basic_auth: Some(("user".to_string(), "secret".to_string())),
+                password: Some("hunter2".to_string()),
+                key: b"key".to_vec(),
+                viewers: Some(Mutex::new(
+                    std::iter::once(("alice".to_string(), 0)).collect(),
+                )),
+                link_lifetime: Duration::from_secs(60),
+                limits,
+            }),
+        }
+    }
+
+    fn limits() -> (Limits, RemoteHandle<()>) {
+        Limits::start(
+            LimitOptions {
+                max_viewers: None,
+                max_viewers_per_address: 1,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                rate_limit: None,
+            },
+            TrustedProxies::new(Vec::new()),
+        )
+    }
+
+    #[tokio::test]
+    async fn links() {
+        let (limits, _handle) = limits();
+        let auth = auth(limits);
+
+        let minute = Duration::from_secs(60);
+
+        let token = auth.sign("alice", minute);
+        assert!(matches!(auth.verify(&token), Some(remaining) if remaining <= 60));
+        assert!(auth.verify(&auth.sign(PASSWORD_VIEWER, minute)).is_some());
+
+        // not invited, expired, or signed with something else
+        assert!(auth.verify(&auth.sign("mallory", minute)).is_none());
+        assert!(auth.verify(&auth.sign("alice", Duration::ZERO)).is_none());
+        let forged = token.replacen("alice", "bob", 1);
+        assert!(auth.verify(&forged).is_none());
+        assert!(auth.verify("alice").is_none());
+
+        // revoked, and still revoked once they're added back
+        auth.viewers().unwrap().lock().unwrap().clear();
+        assert!(auth.verify(&token).is_none());
+        auth.viewers()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .insert("alice".to_string(), unix_time() + 1);
+        assert!(auth.verify(&token).is_none());
+    }
+
+    #[tokio::test]
+    async fn basic_auth_guesses() {
+        let (limits, _handle) = limits();
+        let auth = auth(limits.clone());
+        let header = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+
+        let attempt = warp::test::request()
+            .remote_addr("1.2.3.4:1234".parse().unwrap())
+            .filter(&limits.attempt())
+            .await
+            .unwrap();
+        assert!(auth.check_basic(Some(&header("user:secret")), &attempt));
+        assert!(!auth.check_basic(Some(&header("user:wrong")), &attempt));
+        assert!(!auth.check_basic(Some(&header("other:secret")), &attempt));
+        assert!(!auth.check_basic(None, &attempt));
+
+        while attempt.allowed() {
+            attempt.failed();
+        }
+        // even the right password, until it has waited
+        assert!(!auth.check_basic(Some(&header("user:secret")), &attempt));
+    }
+}
@@ -0,0 +1,126 @@
+use bytes::Bytes;
+use futures::Stream;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use warp::{
+    http::{header::CONTENT_LENGTH, HeaderValue},
+    hyper::{self, body::HttpBody, Body},
+    reply::Response,
+};
+
+type OnChunk = Box<dyn Fn(u64) + Send>;
+type OnFinish = Box<dyn FnOnce(u64, bool) + Send>;
+
+/// counts a body as it's handed to the connection, so range requests and
+/// aborted downloads only count what was actually sent
+struct CountedBody {
+    inner: Body,
+    sent: u64,
+    on_chunk: OnChunk,
+    on_finish: Option<OnFinish>,
+}
+
+impl CountedBody {
+    fn finish(&mut self, complete: bool) {
+        if let Some(on_finish) = self.on_finish.take() {
+            on_finish(self.sent, complete);
+        }
+    }
+}
+
+impl Stream for CountedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let bytes = chunk.len() as u64;
+                self.sent += bytes;
+                (self.on_chunk)(bytes);
+            }
+            Poll::Ready(None) => self.finish(true),
+            _ => {}
+        }
+
+        poll
+    }
+}
+
+impl Drop for CountedBody {
+    fn drop(&mut self) {
+        // hyper drops the body early when the client goes away
+        self.finish(false);
+    }
+}
+
+/// `on_chunk` gets each chunk's size, `on_finish` the total and whether it all went out
+pub fn count_body<C, F>(response: Response, on_chunk: C, on_finish: F) -> Response
+where
+    C: Fn(u64) + Send + 'static,
+    F: FnOnce(u64, bool) + Send + 'static,
+{
+    let (mut parts, body) = response.into_parts();
+
+    // a streamed body would be sent chunked unless we keep its length
+    match HttpBody::size_hint(&body).exact() {
+        // 304s and HEADs, nothing to count so leave them for hyper to handle
+        Some(0) => {
+            on_finish(0, true);
+            return Response::from_parts(parts, body);
+        }
+        Some(len) if !parts.headers.contains_key(CONTENT_LENGTH) => {
+            parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+        _ => {}
+    }
+
+    let counted = CountedBody {
+        inner: body,
+        sent: 0,
+        on_chunk: Box::new(on_chunk),
+        on_finish: Some(Box::new(on_finish)),
+    };
+
+    Response::from_parts(parts, Body::wrap_stream(counted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Finished = Arc<Mutex<Option<(u64, bool)>>>;
+
+    fn counted(body: Body) -> (Response, Finished) {
+        let finished = Arc::new(Mutex::new(None));
+        let response = {
+            let finished = finished.clone();
+            count_body(
+                Response::new(body),
+                |_bytes| {},
+                move |bytes, complete| {
+                    *finished.lock().unwrap() = Some((bytes, complete));
+                },
+            )
+        };
+        (response, finished)
+    }
+
+    #[tokio::test]
+    async fn lengths() {
+        let (response, finished) = counted(Body::empty());
+        assert_eq!(HttpBody::size_hint(response.body()).exact(), Some(0));
+        assert!(!response.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(*finished.lock().unwrap(), Some((0, true)));
+
+        let (response, finished) = counted(Body::from("hello"));
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+        assert_eq!(*finished.lock().unwrap(), Some((5, true)));
+    }
+}
@@ -0,0 +1,618 @@
+use super::{
+    client::{self, TrustedProxies, ViewerId},
+    limits::{Attempt, Limits},
+};
+use crate::helpers::*;
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+use warp::{
+    reply::Response,
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
+
+const HISTORY_LEN: usize = 100;
+const MAX_MESSAGE_LEN: usize = 500;
+const MAX_NICK_LEN: usize = 24;
+// room for MAX_MESSAGE_LEN escaped characters and the json around them
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+
+// everyone gets this many messages per window, on top of any slow mode
+const RATE_LIMIT_MESSAGES: usize = 5;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Join { nick: String },
+    Message { text: String },
+    Auth { password: String },
+    Mute { nick: String },
+    Unmute { nick: String },
+    Delete { id: u64 },
+    Slow { seconds: u64 },
+}
+
+#[derive(Serialize, Clone)]
+pub struct ChatMessage {
+    id: u64,
+    nick: String,
+    text: String,
+    /// unix milliseconds
+    time: u64,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    History { messages: Vec<ChatMessage> },
+    Joined { nick: String, moderator: bool },
+    Moderator,
+    Message(ChatMessage),
+    Deleted { id: u64 },
+    Slow { seconds: u64 },
+    System { text: String },
+    Error { text: String },
+}
+
+/// who's talking, for mutes and rate limits that last across reconnects
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Sender {
+    /// their session, so one mute doesn't silence everyone behind the same address
+    Viewer(ViewerId),
+    /// no session or address, so it only speaks for itself
+    Connection(u64),
+}
+
+impl fmt::Display for Sender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sender::Viewer(id) => write!(f, "{}", id),
+            Sender::Connection(id) => write!(f, "connection {}", id),
+        }
+    }
+}
+
+/// nicks are unique however they're capitalized
+fn nick_key(nick: &str) -> String {
+    nick.to_lowercase()
+}
+
+struct Room {
+    sender: broadcast::Sender<ServerMessage>,
+    history: VecDeque<ChatMessage>,
+    next_id: u64,
+    // nick_key -> sender, of everyone currently connected
+    members: HashMap<String, Sender>,
+    muted: HashSet<Sender>,
+    // when each sender's recent messages were sent
+    recent: HashMap<Sender, VecDeque<Instant>>,
+    slow_mode: Option<Duration>,
+}
+
+impl Room {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(64);
+        Self {
+            sender,
+            history: VecDeque::new(),
+            next_id: 1,
+            members: HashMap::new(),
+            muted: HashSet::new(),
+            recent: HashMap::new(),
+            slow_mode: None,
+        }
+    }
+
+    fn broadcast(&self, message: ServerMessage) {
+        let _ignore = self.sender.send(message);
+    }
+
+    fn member(&self, nick: &str) -> std::result::Result<Sender, String> {
+        self.members
+            .get(&nick_key(nick))
+            .cloned()
+            .ok_or_else(|| format!("{} isn't here", nick))
+    }
+
+    /// slow mode and the rate limit, remembering the message if it's allowed
+    fn check_rate(&mut self, sender: Sender, now: Instant) -> std::result::Result<(), String> {
+        // forget about senders that have been quiet
+        let window = RATE_LIMIT_WINDOW.max(self.slow_mode.unwrap_or_default());
+        self.recent.retain(|_, recent| {
+            recent
+                .back()
+                .map(|last| now - *last <= window)
+                .unwrap_or(false)
+        });
+
+        let recent = self.recent.entry(sender).or_default();
+        if let (Some(slow_mode), Some(last)) = (self.slow_mode, recent.back()) {
+            if now - *last < slow_mode {
+                return Err(format!(
+                    "slow mode is on, wait {} seconds between messages",
+                    slow_mode.as_secs()
+                ));
+            }
+        }
+
+        while let Some(oldest) = recent.front() {
+            if now - *oldest > RATE_LIMIT_WINDOW {
+                recent.pop_front();
+            } else {
+                break;
+            }
+        }
+        if recent.len() >= RATE_LIMIT_MESSAGES {
+            return Err("you're sending messages too fast".to_string());
+        }
+        recent.push_back(now);
+
+        Ok(())
+    }
+}
+
+/// chat rooms, one per stream
+#[derive(Clone)]
+pub struct Chat {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+    moderator_password: Option<Arc<str>>,
+    next_connection: Arc<AtomicU64>,
+}
+
+struct Connection {
+    sender: Sender,
+    nick: Option<String>,
+    moderator: bool,
+    attempt: Attempt,
+}
+
+impl Chat {
+    pub fn new(moderator_password: Option<String>) -> Self {
+        Self {
+            rooms: Default::default(),
+            moderator_password: moderator_password.map(Into::into),
+            next_connection: Default::default(),
+        }
+    }
+
+    async fn handle_socket(
+        self,
+        socket: WebSocket,
+        room_name: String,
+        viewer: Option<ViewerId>,
+        attempt: Attempt,
+    ) {
+        let (mut socket_sender, mut socket_receiver) = socket.split();
+
+        // messages for just this connection, and everything from the room
+        let (direct_sender, mut direct_receiver) = mpsc::unbounded::<ServerMessage>();
+        let mut room_receiver = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let room = rooms.entry(room_name.clone()).or_insert_with(Room::new);
+
+            let _ignore = direct_sender.unbounded_send(ServerMessage::History {
+                messages: room.history.iter().cloned().collect(),
+            });
+            if let Some(slow_mode) = room.slow_mode {
+                let _ignore = direct_sender.unbounded_send(ServerMessage::Slow {
+                    seconds: slow_mode.as_secs(),
+                });
+            }
+
+            room.sender.subscribe()
+        };
+
+        let writer = async move {
+            loop {
+                let message = tokio::select! {
+                    message = direct_receiver.next() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                    message = room_receiver.recv() => match message {
+                        Ok(message) => message,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                };
+
+                let text = match serde_json::to_string(&message) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        warn!("chat: {}", e);
+                        continue;
+                    }
+                };
+
+                if socket_sender.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let writer = tokio::spawn(writer);
+
+        let sender = match viewer {
+            Some(viewer) => Sender::Viewer(viewer),
+            None => Sender::Connection(self.next_connection.fetch_add(1, Ordering::SeqCst)),
+        };
+        let mut connection = Connection {
+            sender,
+            nick: None,
+            moderator: false,
+            attempt,
+        };
+
+        while let Some(Ok(message)) = socket_receiver.next().await {
+            if message.is_close() {
+                break;
+            }
+
+            let text = match message.to_str() {
+                Ok(text) => text,
+                Err(()) => continue,
+            };
+
+            let reply = match serde_json::from_str::<ClientMessage>(text) {
+                Ok(message) => self.handle_message(&room_name, &mut connection, message),
+                Err(_) => Err("bad message".to_string()),
+            };
+
+            match reply {
+                Ok(Some(message)) => {
+                    let _ignore = direct_sender.unbounded_send(message);
+                }
+                Ok(None) => {}
+                Err(text) => {
+                    let _ignore = direct_sender.unbounded_send(ServerMessage::Error { text });
+                }
+            }
+        }
+
+        if let Some(nick) = connection.nick {
+            let mut rooms = self.rooms.lock().unwrap();
+            if let Some(room) = rooms.get_mut(&room_name) {
+                room.members.remove(&nick_key(&nick));
+            }
+        }
+
+        drop(direct_sender);
+        writer.abort();
+    }
+
+    /// Ok(Some) is a reply for just this connection, Err is an error for it
+    fn handle_message(
+        &self,
+        room_name: &str,
+        connection: &mut Connection,
+        message: ClientMessage,
+    ) -> std::result::Result<Option<ServerMessage>, String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(room_name.to_string()).or_insert_with(Room::new);
+
+        match message {
+            ClientMessage::Join { nick } => {
+                let nick = nick.trim().to_string();
+                let valid = !nick.is_empty()
+                    && nick.chars().count() <= MAX_NICK_LEN
+                    && nick
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+                if !valid {
+                    return Err(format!(
+                        "nicknames are up to {} letters, numbers, _ or -",
+                        MAX_NICK_LEN
+                    ));
+                }
+
+                let key = nick_key(&nick);
+                let own = connection.nick.as_deref().map(nick_key);
+                if room.members.contains_key(&key) && own.as_ref() != Some(&key) {
+                    return Err(format!("{} is already taken", nick));
+                }
+
+                if let Some(own) = own {
+                    room.members.remove(&own);
+                }
+                room.members.insert(key, connection.sender.clone());
+                connection.nick = Some(nick.clone());
+
+                Ok(Some(ServerMessage::Joined {
+                    nick,
+                    moderator: connection.moderator,
+                }))
+            }
+
+            ClientMessage::Message { text } => {
+                let nick = connection
+                    .nick
+                    .clone()
+                    .ok_or_else(|| "pick a nickname first".to_string())?;
+
+                let text = text.trim();
+                if text.is_empty() {
+                    return Ok(None);
+                }
+                if text.chars().count() > MAX_MESSAGE_LEN {
+                    return Err(format!("messages are up to {} characters", MAX_MESSAGE_LEN));
+                }
+
+                if room.muted.contains(&connection.sender) {
+                    return Err("you are muted".to_string());
+                }
+
+                if !connection.moderator {
+                    room.check_rate(connection.sender.clone(), Instant::now())?;
+                }
+
+                let message = ChatMessage {
+                    id: room.next_id,
+                    nick,
+                    text: text.to_string(),
+                    time: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                };
+                room.next_id += 1;
+
+                room.history.push_back(message.clone());
+                while room.history.len() > HISTORY_LEN {
+                    room.history.pop_front();
+                }
+
+                room.broadcast(ServerMessage::Message(message));
+                Ok(None)
+            }
+
+            ClientMessage::Auth { password } => {
+                let moderator_password = match &self.moderator_password {
+                    Some(moderator_password) => moderator_password,
+                    None => return Err("wrong password".to_string()),
+                };
+
+                if !connection.attempt.allowed() {
+                    return Err("too many tries, wait a bit".to_string());
+                }
+                if !secrets_match(&password, moderator_password) {
+                    connection.attempt.failed();
+                    return Err("wrong password".to_string());
+                }
+
+                connection.moderator = true;
+                info!("chat moderator logged in from {}", connection.sender);
+                Ok(Some(ServerMessage::Moderator))
+            }
+
+            ClientMessage::Mute { nick } => {
+                require_moderator(connection)?;
+
+                let sender = room.member(&nick)?;
+                room.muted.insert(sender);
+                room.broadcast(ServerMessage::System {
+                    text: format!("{} was muted", nick),
+                });
+
+                Ok(None)
+            }
+
+            ClientMessage::Unmute { nick } => {
+                require_moderator(connection)?;
+
+                let sender = room.member(&nick)?;
+                room.muted.remove(&sender);
+                room.broadcast(ServerMessage::System {
+                    text: format!("{} was unmuted", nick),
+                });
+
+                Ok(None)
+            }
+
+            ClientMessage::Delete { id } => {
+                require_moderator(connection)?;
+
+                room.history.retain(|message| message.id != id);
+                room.broadcast(ServerMessage::Deleted { id });
+
+                Ok(None)
+            }
+
+            ClientMessage::Slow { seconds } => {
+                require_moderator(connection)?;
+
+                room.slow_mode = if seconds == 0 {
+                    None
+                } else {
+                    Some(Duration::from_secs(seconds))
+                };
+                room.broadcast(ServerMessage::Slow { seconds });
+
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn require_moderator(connection: &Connection) -> std::result::Result<(), String> {
+    if connection.moderator {
+        Ok(())
+    } else {
+        Err("only moderators can do that".to_string())
+    }
+}
+
+/// websocket at GET /chat for `room`
+pub fn route(
+    chat: Chat,
+    room: String,
+    proxies: TrustedProxies,
+    limits: &Limits,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("chat")
+        .and(proxies.client_ip())
+        .and(client::viewer_session())
+        .and(limits.attempt())
+        .and(warp::ws())
+        .map(
+            move |client_ip: Option<IpAddr>, session: Option<String>, attempt: Attempt, ws: Ws| {
+                let chat = chat.clone();
+                let room = room.clone();
+                let viewer = match (session, client_ip) {
+                    (Some(session), _) => Some(ViewerId::Session(session)),
+                    (None, Some(ip)) => Some(ViewerId::Ip(ip)),
+                    (None, None) => None,
+                };
+
+                ws.max_message_size(MAX_FRAME_SIZE)
+                    .max_frame_size(MAX_FRAME_SIZE)
+                    .on_upgrade(move |socket| chat.handle_socket(socket, room, viewer, attempt))
+                    .into_response()
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Sender = Sender::Viewer(ViewerId::Ip(IpAddr::V4(std::net::Ipv4Addr::new(
+        10, 0, 0, 1,
+    ))));
+
+    fn connection(sender: Sender) -> Connection {
+        Connection {
+            sender,
+            nick: None,
+            moderator: false,
+            attempt: Attempt::unlimited(),
+        }
+    }
+
+    fn send(
+        chat: &Chat,
+        connection: &mut Connection,
+        message: ClientMessage,
+    ) -> std::result::Result<Option<ServerMessage>, String> {
+        chat.handle_message("room", connection, message)
+    }
+
+    fn say(chat: &Chat, connection: &mut Connection) -> std::result::Result<(), String> {
+        let text = "hi".to_string();
+        send(chat, connection, ClientMessage::Message { text }).map(|_| ())
+    }
+
+    fn join(chat: &Chat, sender: Sender, nick: &str) -> Connection {
+        let mut connection = connection(sender);
+        let nick = nick.to_string();
+        send(chat, &mut connection, ClientMessage::Join { nick }).unwrap();
+        connection
+    }
+
+    fn moderator(chat: &Chat) -> Connection {
+        let mut moderator = connection(Sender::Connection(0));
+        let password = "hunter2".to_string();
+        send(chat, &mut moderator, ClientMessage::Auth { password }).unwrap();
+        moderator
+    }
+
+    #[test]
+    fn nicks() {
+        let chat = Chat::new(None);
+        let _alice = join(&chat, A, "Alice");
+
+        let mut other = connection(Sender::Connection(1));
+        let nick = "ALICE".to_string();
+        assert!(send(&chat, &mut other, ClientMessage::Join { nick }).is_err());
+
+        let room = &chat.rooms.lock().unwrap()["room"];
+        assert_eq!(room.member("alice"), Ok(A));
+        assert!(room.member("bob").is_err());
+    }
+
+    #[test]
+    fn mute() {
+        let chat = Chat::new(Some("hunter2".to_string()));
+        let mut alice = join(&chat, A, "alice");
+        let other_session = Sender::Viewer(ViewerId::Session("0".repeat(32)));
+        let mut bob = join(&chat, other_session, "bob");
+
+        let mut wrong = connection(Sender::Connection(2));
+        let password = "guess".to_string();
+        assert!(send(&chat, &mut wrong, ClientMessage::Auth { password }).is_err());
+        let nick = "alice".to_string();
+        assert!(send(&chat, &mut wrong, ClientMessage::Mute { nick }).is_err());
+
+        let mut moderator = moderator(&chat);
+        let nick = "ALICE".to_string();
+        send(&chat, &mut moderator, ClientMessage::Mute { nick }).unwrap();
+        assert_eq!(say(&chat, &mut alice), Err("you are muted".to_string()));
+        // even after reconnecting under another nick
+        let mut alice = join(&chat, A, "alice2");
+        assert!(say(&chat, &mut alice).is_err());
+        assert!(say(&chat, &mut bob).is_ok());
+
+        let nick = "alice2".to_string();
+        send(&chat, &mut moderator, ClientMessage::Unmute { nick }).unwrap();
+        assert!(say(&chat, &mut alice).is_ok());
+    }
+
+    #[test]
+    fn rate_limit() {
+        let chat = Chat::new(Some("hunter2".to_string()));
+        let mut alice = join(&chat, A, "alice");
+
+        for _ in 0..RATE_LIMIT_MESSAGES {
+            say(&chat, &mut alice).unwrap();
+        }
+        assert!(say(&chat, &mut alice).is_err());
+
+        // a new connection doesn't start over
+        let mut alice = join(&chat, A, "alice2");
+        assert!(say(&chat, &mut alice).is_err());
+
+        // someone else isn't held up, and moderators aren't limited
+        let mut bob = join(&chat, Sender::Connection(1), "bob");
+        assert!(say(&chat, &mut bob).is_ok());
+        let mut moderator = moderator(&chat);
+        moderator.nick = Some("mod".to_string());
+        for _ in 0..RATE_LIMIT_MESSAGES * 2 {
+            say(&chat, &mut moderator).unwrap();
+        }
+
+        // and it wears off
+        let mut rooms = chat.rooms.lock().unwrap();
+        let room = rooms.get_mut("room").unwrap();
+        let later = Instant::now() + RATE_LIMIT_WINDOW + Duration::from_secs(1);
+        assert!(room.check_rate(A, later).is_ok());
+    }
+
+    #[test]
+    fn slow_mode() {
+        let chat = Chat::new(Some("hunter2".to_string()));
+        let mut alice = join(&chat, A, "alice");
+
+        let mut moderator = moderator(&chat);
+        send(&chat, &mut moderator, ClientMessage::Slow { seconds: 30 }).unwrap();
+
+        say(&chat, &mut alice).unwrap();
+        assert!(say(&chat, &mut alice).is_err());
+
+        let mut rooms = chat.rooms.lock().unwrap();
+        let room = rooms.get_mut("room").unwrap();
+        let now = Instant::now();
+        assert!(room.check_rate(A, now + Duration::from_secs(10)).is_err());
+        assert!(room.check_rate(A, now + Duration::from_secs(31)).is_ok());
+    }
+}
@@ -0,0 +1,447 @@
+use crate::error::*;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+use warp::{
+    http::{uri::Authority, HeaderValue, Version},
+    Filter, Rejection,
+};
+
+const SESSION_COOKIE_NAME: &str = "dash_viewer";
+
+// hex of 16 random bytes
+const SESSION_ID_LEN: usize = 32;
+
+/// what our server knows about the connection a request came in on
+#[derive(Clone, Copy)]
+pub struct Connection {
+    pub remote: SocketAddr,
+    pub version: Version,
+}
+
+/// the peer's address, from our server or from warp's own tls one
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::ext::optional::<Connection>()
+        .and(warp::addr::remote())
+        .map(
+            |connection: Option<Connection>, remote: Option<SocketAddr>| {
+                connection.map(|connection| connection.remote).or(remote)
+            },
+        )
+}
+
+/// None behind warp's tls server, which doesn't tell
+pub fn http_version() -> impl Filter<Extract = (Option<Version>,), Error = Infallible> + Clone {
+    warp::ext::optional::<Connection>()
+        .map(|connection: Option<Connection>| connection.map(|connection| connection.version))
+}
+
+/// an ip range like 10.0.0.0/8, or a single address
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+
+        let network = canonical(
+            network
+                .parse::<IpAddr>()
+                .chain_err(|| format!("{:?} isn't an ip range", s))?,
+        );
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .chain_err(|| format!("{:?} has a bad prefix length", s))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { network, prefix })
+    }
+}
+
+/// ipv4 clients on a dual stack socket show up as ::ffff:1.2.3.4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        ip => ip,
+    }
+}
+
+/// reverse proxies whose X-Forwarded-For and Forwarded headers we believe
+#[derive(Clone)]
+pub struct TrustedProxies {
+    cidrs: Arc<Vec<Cidr>>,
+}
+
+impl TrustedProxies {
+    pub fn new(cidrs: Vec<Cidr>) -> Self {
+        Self {
+            cidrs: Arc::new(cidrs),
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// walks the proxy chain back from us, stopping at the first hop we don't trust
+    fn resolve(
+        &self,
+        remote: Option<IpAddr>,
+        forwarded: Option<&str>,
+        forwarded_for: Option<&str>,
+    ) -> Option<IpAddr> {
+        let mut client = canonical(remote?);
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+
+        // Forwarded is the standard one, if a proxy sends both
+        let hops: Vec<&str> = match (forwarded, forwarded_for) {
+            (Some(forwarded), _) => forwarded
+                .split(',')
+                .filter_map(|element| {
+                    element.split(';').find_map(|pair| {
+                        let (key, value) = pair.trim().split_once('=')?;
+                        if key.eq_ignore_ascii_case("for") {
+                            Some(value.trim().trim_matches('"'))
+                        } else {
+                            None
+                        }
+                    })
+                })
+                .collect(),
+            (None, Some(forwarded_for)) => forwarded_for.split(',').map(str::trim).collect(),
+            (None, None) => Vec::new(),
+        };
+
+        for hop in hops.iter().rev() {
+            match parse_hop(hop) {
+                Some(ip) => {
+                    client = ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                // "unknown" or an obfuscated name, the last proxy is all we know
+                None => break,
+            }
+        }
+
+        Some(client)
+    }
+
+    /// address of the viewer, or of the client behind our proxies
+    pub fn client_ip(&self) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+        let proxies = self.clone();
+
+        remote_addr()
+            .and(warp::header::optional::<String>("forwarded"))
+            .and(warp::header::optional::<String>("x-forwarded-for"))
+            .map(
+                move |addr: Option<SocketAddr>,
+                      forwarded: Option<String>,
+                      forwarded_for: Option<String>| {
+                    proxies.resolve(
+                        addr.map(|addr| addr.ip()),
+                        forwarded.as_deref(),
+                        forwarded_for.as_deref(),
+                    )
+                },
+            )
+    }
+
+    /// the scheme and host viewers used to reach us, like https://example.com/
+    pub fn public_base(
+        &self,
+        protocol: &'static str,
+    ) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        let proxies = self.clone();
+
+        remote_addr()
+            .and(warp::host::optional())
+            .and(warp::header::optional::<String>("forwarded"))
+            .and(warp::header::optional::<String>("x-forwarded-proto"))
+            .and(warp::header::optional::<String>("x-forwarded-host"))
+            .map(
+                move |addr: Option<SocketAddr>,
+                      host: Option<Authority>,
+                      forwarded: Option<String>,
+                      forwarded_proto: Option<String>,
+                      forwarded_host: Option<String>| {
+                    let mut protocol = protocol.to_string();
+                    let mut host = host
+                        .map(|host| host.to_string())
+                        .unwrap_or_else(|| "localhost".to_string());
+
+                    let trusted = addr
+                        .map(|addr| proxies.is_trusted(canonical(addr.ip())))
+                        .unwrap_or(false);
+                    if trusted {
+                        // the first element is from the proxy the viewer connected to
+                        let first = forwarded
+                            .as_deref()
+                            .and_then(|forwarded| forwarded.split(',').next())
+                            .unwrap_or_default();
+                        let pairs = first.split(';').filter_map(|pair| {
+                            let (key, value) = pair.trim().split_once('=')?;
+                            Some((key.to_ascii_lowercase(), value.trim().trim_matches('"')))
+                        });
+                        let mut forwarded_proto = forwarded_proto;
+                        let mut forwarded_host = forwarded_host;
+                        for (key, value) in pairs {
+                            match key.as_str() {
+                                "proto" => forwarded_proto = Some(value.to_string()),
+                                "host" => forwarded_host = Some(value.to_string()),
+                                _ => {}
+                            }
+                        }
+
+                        if let Some(value) = forwarded_proto {
+                            let value = value.split(',').next().unwrap_or_default().trim();
+                            if value == "http" || value == "https" {
+                                protocol = value.to_string();
+                            }
+                        }
+                        if let Some(value) = forwarded_host {
+                            let value = value.split(',').next().unwrap_or_default().trim();
+                            if value.parse::<Authority>().is_ok() {
+                                host = value.to_string();
+                            }
+                        }
+                    }
+
+                    format!("{}://{}/", protocol, host)
+                },
+            )
+    }
+}
+
+/// 1.2.3.4, 1.2.3.4:5678, [2001:db8::1]:5678 or 2001:db8::1
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(canonical(ip));
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(canonical(addr.ip()));
+    }
+
+    // Forwarded allows a bracketed v6 address without a port
+    hop.strip_prefix('[')
+        .and_then(|hop| hop.strip_suffix(']'))
+        .and_then(|ip| ip.parse::<Ipv6Addr>().ok())
+        .map(|ip| canonical(IpAddr::V6(ip)))
+}
+
+/// one browser session, or one address for players that don't keep cookies
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ViewerId {
+    Session(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for ViewerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ViewerId::Session(id) => write!(f, "session {}", &id[..8]),
+            ViewerId::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+fn session_signature(id: &str) -> Hmac<Sha256> {
+    // signs session cookies, so viewers can't make up their own
+    static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+    let key = KEY.get_or_init(|| {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    });
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key size");
+    mac.update(id.as_bytes());
+    mac
+}
+
+/// `id.signature`
+fn new_session() -> String {
+    let mut bytes = [0; SESSION_ID_LEN / 2];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let id = hex::encode(bytes);
+    let signature = hex::encode(session_signature(&id).finalize().into_bytes());
+
+    format!("{}.{}", id, signature)
+}
+
+/// the session id, if we made this cookie in this run
+fn verify_session(cookie: &str) -> Option<String> {
+    let (id, signature) = cookie.split_once('.')?;
+    if id.len() != SESSION_ID_LEN || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    session_signature(id)
+        .verify_slice(&hex::decode(signature).ok()?)
+        .ok()?;
+
+    Some(id.to_string())
+}
+
+/// the viewer session id, if the cookie is one we made
+pub fn viewer_session() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::cookie::optional::<String>(SESSION_COOKIE_NAME)
+        .map(|cookie: Option<String>| cookie.as_deref().and_then(verify_session))
+}
+
+pub fn new_session_cookie() -> HeaderValue {
+    // no Max-Age, so it ends with the browser session
+    HeaderValue::from_str(&format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax",
+        SESSION_COOKIE_NAME,
+        new_session()
+    ))
+    .expect("hex is ascii")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parsing() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(cidr.contains(ip("::ffff:10.0.0.1")));
+        assert!(!cidr.contains(ip("::1")));
+
+        let single: Cidr = "192.168.1.5".parse().unwrap();
+        assert!(single.contains(ip("192.168.1.5")));
+        assert!(!single.contains(ip("192.168.1.6")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("1.2.3.4")));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn hops() {
+        assert_eq!(parse_hop("1.2.3.4"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_hop("1.2.3.4:5678"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_hop("[2001:db8::1]:5678"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_hop("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_hop("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_hop("unknown"), None);
+        assert_eq!(parse_hop("_hidden"), None);
+    }
+
+    #[test]
+    fn forwarded_headers() {
+        let proxies = TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]);
+
+        // untrusted peers can't claim to be someone else
+        assert_eq!(
+            proxies.resolve(Some(ip("1.2.3.4")), Some("for=5.6.7.8"), Some("5.6.7.8")),
+            Some(ip("1.2.3.4"))
+        );
+
+        assert_eq!(
+            proxies.resolve(Some(ip("10.0.0.1")), None, Some("5.6.7.8, 10.0.0.2")),
+            Some(ip("5.6.7.8"))
+        );
+
+        // stops at the first hop we don't trust, from our end
+        assert_eq!(
+            proxies.resolve(Some(ip("10.0.0.1")), None, Some("6.6.6.6, 5.6.7.8")),
+            Some(ip("5.6.7.8"))
+        );
+
+        // Forwarded wins over X-Forwarded-For
+        assert_eq!(
+            proxies.resolve(
+                Some(ip("10.0.0.1")),
+                Some("for=\"[2001:db8::1]:4711\";proto=https, For=10.0.0.2"),
+                Some("5.6.7.8")
+            ),
+            Some(ip("2001:db8::1"))
+        );
+
+        assert_eq!(
+            proxies.resolve(Some(ip("10.0.0.1")), Some("for=unknown"), None),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(
+            proxies.resolve(Some(ip("10.0.0.1")), None, None),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(proxies.resolve(None, None, Some("5.6.7.8")), None);
+    }
+
+    #[test]
+    fn sessions() {
+        let cookie = new_session();
+        let id = verify_session(&cookie).unwrap();
+        assert_eq!(id.len(), SESSION_ID_LEN);
+        assert!(cookie.starts_with(&id));
+
+        // made up ids and tampered signatures
+        assert_eq!(verify_session(&id), None);
+        let (_, signature) = cookie.split_once('.').unwrap();
+        let other = "0".repeat(SESSION_ID_LEN);
+        assert_eq!(verify_session(&format!("{}.{}", other, signature)), None);
+        assert_eq!(verify_session(&format!("{}.{}", id, "00".repeat(32))), None);
+        assert_eq!(verify_session(&format!("{}.", id)), None);
+        assert_eq!(verify_session(""), None);
+    }
+}
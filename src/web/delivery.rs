@@ -0,0 +1,448 @@
+use super::metrics::file_kind;
+use crate::store::{content_type, FileVersion, Segments, MANIFEST_NAME};
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use log::*;
+use std::{io::Write, time::UNIX_EPOCH};
+use warp::{
+    filters::BoxedFilter,
+    http::{
+        header::{
+            ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+            CONTENT_TYPE, ETAG, VARY,
+        },
+        HeaderValue, StatusCode,
+    },
+    path::Peek,
+    reply::Response,
+    Filter, Reply,
+};
+
+// the manifest changes every segment, so caches have to check with us each time
+const MANIFEST_CACHE_CONTROL: &str = "no-cache";
+
+// segment names are unique per encoder run, so they never change once written
+const SEGMENT_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// what to compress the manifest with, from an Accept-Encoding header
+    pub fn choose(accept_encoding: Option<&str>) -> Option<Self> {
+        let accept_encoding = accept_encoding?;
+        [Encoding::Brotli, Encoding::Gzip]
+            .iter()
+            .copied()
+            .find(|encoding| accepts(accept_encoding, encoding.name()))
+    }
+
+    pub fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                writer.write_all(data)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// `coding` is listed and not turned off with q=0
+fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let disabled = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .map(|q| q == 0.0)
+                .unwrap_or(false)
+        });
+
+        name.eq_ignore_ascii_case(coding) && !disabled
+    })
+}
+
+/// changes whenever the file does, and differs per compression
+fn etag(version: FileVersion, encoding: Option<Encoding>) -> String {
+    let modified = version
+        .modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    match encoding {
+        Some(encoding) => format!("\"{:x}-{:x}-{}\"", version.len, modified, encoding.name()),
+        None => format!("\"{:x}-{:x}\"", version.len, modified),
+    }
+}
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+fn cache_control(name: &str) -> Option<&'static str> {
+    match file_kind(name) {
+        "manifest" => Some(MANIFEST_CACHE_CONTROL),
+        "init" | "segment" => Some(SEGMENT_CACHE_CONTROL),
+        _ => None,
+    }
+}
+
+/// the manifest and segments, with caching headers and a compressed manifest
+pub fn route(segments: Segments) -> BoxedFilter<(Response,)> {
+    let files: BoxedFilter<(Response,)> = match segments.clone() {
+        Segments::Dir(output_dir) => warp::fs::dir(output_dir).map(Reply::into_response).boxed(),
+
+        // ranges like warp::fs does for the dir
+        Segments::Memory(store) => warp::get()
+            .or(warp::head())
+            .unify()
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(warp::header::optional::<String>("range"))
+            .and_then(move |name: String, range: Option<String>| {
+                let data = store.get(&name);
+                async move {
+                    data.map(|data| ranged_response(&name, data, range.as_deref()))
+                        .ok_or_else(warp::reject::not_found)
+                }
+            })
+            .boxed(),
+    };
+
+    let manifest = {
+        let segments = segments.clone();
+        warp::path(MANIFEST_NAME)
+            .and(warp::path::end())
+            .and(warp::get().or(warp::head()).unify())
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and_then(move |accept_encoding: Option<String>| {
+                let segments = segments.clone();
+                async move {
+                    // taken before reading, so a change in between makes the next request refetch
+                    let version = segments.version(MANIFEST_NAME);
+                    match segments.read(MANIFEST_NAME).await {
+                        Ok(Some(data)) => {
+                            let encoding = Encoding::choose(accept_encoding.as_deref());
+                            Ok(manifest_response(data, version, encoding))
+                        }
+                        Ok(None) => Err(warp::reject::not_found()),
+                        Err(e) => {
+                            warn!("reading manifest: {}", e);
+                            Err(warp::reject::not_found())
+                        }
+                    }
+                }
+            })
+    };
+
+    // answered from the file's version alone, without reading it
+    let not_modified = {
+        let segments = segments.clone();
+        warp::path::peek()
+            .and(warp::header::<String>("if-none-match"))
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and_then(
+                move |peek: Peek, if_none_match: String, accept_encoding: Option<String>| {
+                    let name = peek.as_str();
+                    let encoding = if name == MANIFEST_NAME {
+                        Encoding::choose(accept_encoding.as_deref())
+                    } else {
+                        None
+                    };
+
+                    // the manifest is sent uncompressed when that's smaller, with that etag
+                    let response = segments
+                        .version(name)
+                        .and_then(|version| {
+                            let mut etags = vec![etag(version, encoding)];
+                            if encoding.is_some() {
+                                etags.push(etag(version, None));
+                            }
+                            etags
+                                .into_iter()
+                                .find(|etag| etag_matches(&if_none_match, etag))
+                        })
+                        .map(|etag| {
+                            let mut response = StatusCode::NOT_MODIFIED.into_response();
+                            let headers = response.headers_mut();
+                            headers.insert(
+                                ETAG,
+                                HeaderValue::from_str(&etag).expect("etags are ascii"),
+                            );
+                            if name == MANIFEST_NAME {
+                                headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+                            }
+                            response
+                        })
+                        .ok_or_else(warp::reject::not_found);
+
+                    async move { response }
+                },
+            )
+    };
+
+    warp::path::peek()
+        .and(not_modified.or(manifest).unify().or(files).unify())
+        .map(move |peek: Peek, mut response: Response| {
+            let name = peek.as_str();
+            let has_body = response.status().is_success();
+            let headers = response.headers_mut();
+
+            if let Some(cache_control) = cache_control(name) {
+                headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+                if has_body {
+                    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(name)));
+                }
+            }
+
+            // the manifest sets its own, since it depends on the compression
+            if !headers.contains_key(ETAG) {
+                if let Some(version) = segments.version(name) {
+                    headers.insert(
+                        ETAG,
+                        HeaderValue::from_str(&etag(version, None)).expect("etags are ascii"),
+                    );
+                }
+            }
+
+            response
+        })
+        .boxed()
+}
+
+fn manifest_response(
+    data: Bytes,
+    version: Option<FileVersion>,
+    encoding: Option<Encoding>,
+) -> Response {
+    let compressed = encoding.and_then(|encoding| match encoding.compress(&data) {
+        Ok(compressed) if compressed.len() < data.len() => Some((encoding, compressed)),
+        Ok(_) => None,
+        Err(e) => {
+            warn!("compressing manifest: {}", e);
+            None
+        }
+    });
+
+    let encoding = compressed.as_ref().map(|(encoding, _)| *encoding);
+    let mut response = match &compressed {
+        Some((encoding, compressed)) => {
+            let mut response = file_response(MANIFEST_NAME, compressed.clone().into());
+            response
+                .headers_mut()
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            response
+        }
+        None => file_response(MANIFEST_NAME, data),
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+    if let Some(version) = version {
+        headers.insert(
+            ETAG,
+            HeaderValue::from_str(&etag(version, encoding)).expect("etags are ascii"),
+        );
+    }
+    response
+}
+
+fn file_response(name: &str, data: Bytes) -> Response {
+    let len = data.len();
+    let mut response = Response::new(data.into());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type(name)));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+    response
+}
+
+/// `bytes=start-end`, `bytes=start-` or `bytes=-suffix` as start and end, None to send it all,
+/// like for multiple ranges. Err if it's past the end.
+fn parse_range(range: &str, len: u64) -> std::result::Result<Option<(u64, u64)>, ()> {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+fn ranged_response(name: &str, data: Bytes, range: Option<&str>) -> Response {
+    let len = data.len() as u64;
+
+    let mut response = match range.map(|range| parse_range(range, len)) {
+        Some(Ok(Some((start, end)))) => {
+            let mut response = file_response(name, data.slice(start as usize..=end as usize));
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            response.headers_mut().insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, len))
+                    .expect("numbers are ascii"),
+            );
+            response
+        }
+        Some(Err(())) => {
+            let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            response.headers_mut().insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len)).expect("numbers are ascii"),
+            );
+            response
+        }
+        _ => file_response(name, data),
+    };
+
+    response
+        .headers_mut()
+        .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::SegmentStore;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=500-5000", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+
+        // sent whole
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=9-1", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=-0", 1000), Ok(None));
+    }
+
+    #[test]
+    fn encodings() {
+        assert!(Encoding::choose(None).is_none());
+        assert!(Encoding::choose(Some("identity")).is_none());
+        assert!(Encoding::choose(Some("gzip, deflate, br")) == Some(Encoding::Brotli));
+        assert!(Encoding::choose(Some("gzip, br;q=0")) == Some(Encoding::Gzip));
+        assert!(Encoding::choose(Some("GZIP;q=0.5")) == Some(Encoding::Gzip));
+    }
+
+    #[tokio::test]
+    async fn memory_ranges() {
+        let store = SegmentStore::new();
+        store.put("chunk-stream0-00001.webm".to_string(), "0123456789".into());
+        let route = route(Segments::Memory(store));
+
+        let response = warp::test::request()
+            .path("/chunk-stream0-00001.webm")
+            .header("range", "bytes=2-4")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.body(), "234");
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+
+        let response = warp::test::request()
+            .path("/chunk-stream0-00001.webm")
+            .header("range", "bytes=20-")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let response = warp::test::request()
+            .path("/chunk-stream0-00001.webm")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "0123456789");
+        assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+    }
+
+    #[tokio::test]
+    async fn manifest_etags() {
+        let store = SegmentStore::new();
+        // too short to get smaller compressed
+        store.put(MANIFEST_NAME.to_string(), "<MPD/>".into());
+        let route = route(Segments::Memory(store.clone()));
+
+        let response = warp::test::request()
+            .path("/stream.mpd")
+            .header("accept-encoding", "br, gzip")
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        let etag = response.headers()[ETAG].clone();
+
+        let response = warp::test::request()
+            .path("/stream.mpd")
+            .header("accept-encoding", "br, gzip")
+            .header("if-none-match", etag.clone())
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag);
+
+        // compressed this time
+        let manifest = "<MPD>".to_string() + &"<Period/>".repeat(100) + "</MPD>";
+        store.put(MANIFEST_NAME.to_string(), manifest.into());
+
+        let response = warp::test::request()
+            .path("/stream.mpd")
+            .header("accept-encoding", "gzip")
+            .header("if-none-match", etag)
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        let etag = response.headers()[ETAG].clone();
+
+        let response = warp::test::request()
+            .path("/stream.mpd")
+            .header("accept-encoding", "gzip")
+            .header("if-none-match", etag)
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+}
@@ -0,0 +1,147 @@
+use super::{tracker::SegmentTracker, Clients};
+use crate::ffmpeg::EncoderState;
+use futures::{future::RemoteHandle, stream, FutureExt, Stream, StreamExt};
+use serde::Serialize;
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+use warp::{reply::Response, sse::Event, Filter, Rejection, Reply};
+
+// 3 segments without a new manifest means ffmpeg stopped producing video
+const OFFLINE_AFTER: Duration = Duration::from_secs(3 * 2);
+
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    Online,
+    Offline,
+    Viewers { count: usize },
+    EncoderRestarted { restarts: u64 },
+    Ended,
+}
+
+impl StreamEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::Online => "online",
+            StreamEvent::Offline => "offline",
+            StreamEvent::Viewers { .. } => "viewers",
+            StreamEvent::EncoderRestarted { .. } => "encoder_restarted",
+            StreamEvent::Ended => "ended",
+        }
+    }
+
+    fn to_sse(&self) -> Event {
+        Event::default()
+            .event(self.name())
+            .json_data(self)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamEvents {
+    sender: broadcast::Sender<StreamEvent>,
+    tracker: SegmentTracker,
+    clients: Clients,
+    encoder: Arc<EncoderState>,
+}
+
+impl StreamEvents {
+    /// watches stream state and broadcasts whatever changed
+    pub fn start(
+        clients: Clients,
+        tracker: SegmentTracker,
+        encoder: Arc<EncoderState>,
+    ) -> (Self, RemoteHandle<()>) {
+        let (sender, _) = broadcast::channel(16);
+
+        let events = Self {
+            sender,
+            tracker,
+            clients,
+            encoder,
+        };
+
+        let (f, handle) = {
+            let events = events.clone();
+            async move {
+                let mut current = events.snapshot();
+                let mut restarts = events.encoder.restarts();
+
+                loop {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+
+                    for event in events.snapshot() {
+                        if !current.contains(&event) {
+                            let _ignore = events.sender.send(event.clone());
+                        }
+                    }
+                    current = events.snapshot();
+
+                    let new_restarts = events.encoder.restarts();
+                    if new_restarts != restarts {
+                        restarts = new_restarts;
+                        let _ignore = events
+                            .sender
+                            .send(StreamEvent::EncoderRestarted { restarts });
+                    }
+                }
+            }
+        }
+        .remote_handle();
+        tokio::spawn(f);
+
+        (events, handle)
+    }
+
+    /// events describing the current state, sent first to new listeners
+    fn snapshot(&self) -> Vec<StreamEvent> {
+        let state = if self.encoder.ended() {
+            StreamEvent::Ended
+        } else if Self::is_online(&self.tracker) {
+            StreamEvent::Online
+        } else {
+            StreamEvent::Offline
+        };
+
+        let count = self.clients.lock().unwrap().len();
+
+        vec![state, StreamEvent::Viewers { count }]
+    }
+
+    /// ffmpeg is still producing video
+    pub fn is_online(tracker: &SegmentTracker) -> bool {
+        tracker
+            .freshness()
+            .manifest_age
+            .map(|age| age < OFFLINE_AFTER)
+            .unwrap_or(false)
+    }
+
+    fn subscribe(&self) -> impl Stream<Item = std::result::Result<Event, Infallible>> {
+        let receiver = self.sender.subscribe();
+        let initial = stream::iter(self.snapshot());
+
+        let updates = stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    // slow listener, just keep going with newer events
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        initial.chain(updates).map(|event| Ok(event.to_sse()))
+    }
+}
+
+/// GET /events as server-sent events
+pub fn route(
+    events: StreamEvents,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("events").and(warp::get()).map(move || {
+        warp::sse::reply(warp::sse::keep_alive().stream(events.subscribe())).into_response()
+    })
+}
@@ -0,0 +1,486 @@
+use super::{
+    client::{self, Cidr, TrustedProxies, ViewerId},
+    Client, Clients,
+};
+use futures::{future::RemoteHandle, FutureExt};
+use log::*;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use warp::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    reject::Reject,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+const FULL: &str = include_str!("full.html");
+
+// forget about addresses that stopped making requests
+const BUCKET_IDLE: Duration = Duration::from_secs(60);
+
+// wrong passwords allowed at once from an address, then one more every 10 seconds
+const ATTEMPT_BURST: f64 = 5.0;
+const ATTEMPT_RATE: f64 = 0.1;
+
+pub struct LimitOptions {
+    pub max_viewers: Option<usize>,
+    /// of max_viewers, so one address can't take them all
+    pub max_viewers_per_address: usize,
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    /// requests per second per address
+    pub rate_limit: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum Limited {
+    Denied,
+    TooManyRequests,
+    Full {
+        html: bool,
+    },
+    /// new viewers of a stream that has ended
+    Ended,
+}
+
+impl Reject for Limited {}
+
+impl Limited {
+    pub fn response(&self) -> Response {
+        match self {
+            Limited::Denied => StatusCode::FORBIDDEN.into_response(),
+
+            Limited::TooManyRequests => {
+                let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+                response
+            }
+
+            Limited::Full { html } => {
+                let mut response = if *html {
+                    warp::reply::html(FULL).into_response()
+                } else {
+                    Response::default()
+                };
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from_static("30"));
+                response
+            }
+
+            Limited::Ended => StatusCode::GONE.into_response(),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        self.tokens = (self.tokens + (now - self.updated).as_secs_f64() * rate).min(burst);
+        self.updated = now;
+    }
+
+    /// refills for the time since the last request, then takes a token if there's one
+    fn take(&mut self, now: Instant, rate: f64, burst: f64) -> bool {
+        self.refill(now, rate, burst);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+type Buckets = Arc<Mutex<HashMap<IpAddr, Bucket>>>;
+
+/// who may connect, how often, and how many at once
+#[derive(Clone)]
+pub struct Limits {
+    options: Arc<LimitOptions>,
+    proxies: TrustedProxies,
+    buckets: Buckets,
+    // wrong passwords, always limited
+    attempts: Buckets,
+}
+
+/// one request's password check, so guesses from an address can be slowed down
+pub struct Attempt {
+    attempts: Buckets,
+    ip: Option<IpAddr>,
+}
+
+impl Attempt {
+    /// whether the address has guesses left, without using one up
+    pub fn allowed(&self) -> bool {
+        let ip = match self.ip {
+            Some(ip) => ip,
+            None => return true,
+        };
+
+        let mut attempts = self.attempts.lock().unwrap();
+        match attempts.get_mut(&ip) {
+            Some(bucket) => {
+                bucket.refill(Instant::now(), ATTEMPT_RATE, ATTEMPT_BURST);
+                bucket.tokens >= 1.0
+            }
+            None => true,
+        }
+    }
+
+    pub fn failed(&self) {
+        if let Some(ip) = self.ip {
+            debug!("{} got a password wrong", ip);
+            let now = Instant::now();
+            self.attempts
+                .lock()
+                .unwrap()
+                .entry(ip)
+                .or_insert_with(|| Bucket::full(ATTEMPT_BURST, now))
+                .take(now, ATTEMPT_RATE, ATTEMPT_BURST);
+        }
+    }
+}
+
+#[cfg(test)]
+impl Attempt {
+    /// for tests that don't get passwords wrong
+    pub fn unlimited() -> Self {
+        Self {
+            attempts: Default::default(),
+            ip: None,
+        }
+    }
+}
+
+impl Limits {
+    pub fn start(options: LimitOptions, proxies: TrustedProxies) -> (Self, RemoteHandle<()>) {
+        let buckets: Buckets = Default::default();
+        let attempts: Buckets = Default::default();
+
+        let (f, handle) = {
+            let buckets = buckets.clone();
+            let attempts = attempts.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(BUCKET_IDLE).await;
+                    buckets
+                        .lock()
+                        .unwrap()
+                        .retain(|_, bucket| bucket.updated.elapsed() < BUCKET_IDLE);
+                    // they've refilled by then anyway
+                    attempts
+                        .lock()
+                        .unwrap()
+                        .retain(|_, bucket| bucket.updated.elapsed() < BUCKET_IDLE);
+                }
+            }
+        }
+        .remote_handle();
+        tokio::spawn(f);
+
+        (
+            Self {
+                options: Arc::new(options),
+                proxies,
+                buckets,
+                attempts,
+            },
+            handle,
+        )
+    }
+
+    fn check_address(&self, ip: IpAddr) -> std::result::Result<(), Limited> {
+        let options = &self.options;
+
+        let allowed =
+            options.allow.is_empty() || options.allow.iter().any(|cidr| cidr.contains(ip));
+        if !allowed || options.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return Err(Limited::Denied);
+        }
+
+        if let Some(rate_limit) = options.rate_limit {
+            // a page load fetches a burst of files, so allow a couple seconds worth at once
+            let rate = rate_limit as f64;
+            let burst = (rate * 2.0).max(10.0);
+
+            let mut buckets = self.buckets.lock().unwrap();
+            let now = Instant::now();
+            let bucket = buckets
+                .entry(ip)
+                .or_insert_with(|| Bucket::full(burst, now));
+
+            if !bucket.take(now, rate, burst) {
+                return Err(Limited::TooManyRequests);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// allow and deny lists and rate limiting, for every request
+    pub fn filter(&self) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        let limits = self.clone();
+
+        self.proxies
+            .client_ip()
+            .and_then(move |client_ip: Option<IpAddr>| {
+                let result = match client_ip {
+                    Some(ip) => limits.check_address(ip).map_err(|limited| {
+                        debug!("{} limited: {:?}", ip, limited);
+                        warp::reject::custom(limited)
+                    }),
+                    None => Ok(()),
+                };
+                async move { result }
+            })
+            .untuple_one()
+    }
+
+    /// for requests that check a password
+    pub fn attempt(&self) -> impl Filter<Extract = (Attempt,), Error = Rejection> + Clone {
+        let attempts = self.attempts.clone();
+
+        self.proxies
+            .client_ip()
+            .map(move |ip: Option<IpAddr>| Attempt {
+                attempts: attempts.clone(),
+                ip,
+            })
+    }
+
+    /// turns away new viewers once a stream has enough
+    pub fn viewer_slot(
+        &self,
+        clients: Clients,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        self.slot(clients, None)
+    }
+
+    /// same, but also counts them as watching, so requests at the same time can't all get the
+    /// last slot
+    pub fn take_viewer_slot(
+        &self,
+        clients: Clients,
+        label: String,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        self.slot(clients, Some(label))
+    }
+
+    fn slot(
+        &self,
+        clients: Clients,
+        label: Option<String>,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        let limits = self.clone();
+
+        self.proxies
+            .client_ip()
+            .and(client::viewer_session())
+            .and(warp::header::optional::<String>("accept"))
+            .and_then(
+                move |client_ip: Option<IpAddr>,
+                      session: Option<String>,
+                      accept: Option<String>| {
+                    let result = match (limits.options.max_viewers, client_ip) {
+                        (Some(max_viewers), Some(ip)) => {
+                            let mut clients = clients.lock().unwrap();
+
+                            // same as how the files route tracks them
+                            let id = match session {
+                                Some(session) => ViewerId::Session(session),
+                                None => ViewerId::Ip(ip),
+                            };
+
+                            let from_address =
+                                clients.values().filter(|client| client.ip == ip).count();
+                            let full = clients.len() >= max_viewers
+                                || from_address >= limits.options.max_viewers_per_address;
+
+                            if full && !clients.contains_key(&id) {
+                                Err(warp::reject::custom(Limited::Full {
+                                    html: accept
+                                        .map(|accept| accept.contains("text/html"))
+                                        .unwrap_or(false),
+                                }))
+                            } else {
+                                if let Some(label) = &label {
+                                    Client::track(&mut clients, id, ip, label);
+                                }
+                                Ok(())
+                            }
+                        }
+                        _ => Ok(()),
+                    };
+                    async move { result }
+                },
+            )
+            .untuple_one()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(3.0, start);
+
+        // the burst, then nothing until it refills
+        assert!(bucket.take(start, 1.0, 3.0));
+        assert!(bucket.take(start, 1.0, 3.0));
+        assert!(bucket.take(start, 1.0, 3.0));
+        assert!(!bucket.take(start, 1.0, 3.0));
+
+        let later = start + Duration::from_millis(500);
+        assert!(!bucket.take(later, 1.0, 3.0));
+        let later = start + Duration::from_millis(1100);
+        assert!(bucket.take(later, 1.0, 3.0));
+        assert!(!bucket.take(later, 1.0, 3.0));
+
+        // never holds more than the burst
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take(much_later, 1.0, 3.0));
+        }
+        assert!(!bucket.take(much_later, 1.0, 3.0));
+    }
+
+    #[tokio::test]
+    async fn password_attempts() {
+        let (limits, _handle) = Limits::start(
+            LimitOptions {
+                max_viewers: None,
+                max_viewers_per_address: 1,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                rate_limit: None,
+            },
+            TrustedProxies::new(Vec::new()),
+        );
+
+        let from =
+            |ip: &str| warp::test::request().remote_addr(format!("{}:1234", ip).parse().unwrap());
+
+        let attempt = from("1.2.3.4").filter(&limits.attempt()).await.unwrap();
+        for _ in 0..ATTEMPT_BURST as usize {
+            assert!(attempt.allowed());
+            attempt.failed();
+        }
+        assert!(!attempt.allowed());
+
+        let attempt = from("1.2.3.4").filter(&limits.attempt()).await.unwrap();
+        assert!(!attempt.allowed());
+        let attempt = from("5.6.7.8").filter(&limits.attempt()).await.unwrap();
+        assert!(attempt.allowed());
+    }
+
+    #[tokio::test]
+    async fn allow_and_deny() {
+        let (limits, _handle) = Limits::start(
+            LimitOptions {
+                max_viewers: None,
+                max_viewers_per_address: 1,
+                allow: vec!["10.0.0.0/8".parse().unwrap()],
+                deny: vec!["10.0.0.13".parse().unwrap()],
+                rate_limit: None,
+            },
+            TrustedProxies::new(Vec::new()),
+        );
+
+        assert!(limits.check_address("10.0.0.1".parse().unwrap()).is_ok());
+        assert!(matches!(
+            limits.check_address("10.0.0.13".parse().unwrap()),
+            Err(Limited::Denied)
+        ));
+        assert!(matches!(
+            limits.check_address("1.2.3.4".parse().unwrap()),
+            Err(Limited::Denied)
+        ));
+    }
+
+    #[tokio::test]
+    async fn viewers_per_address() {
+        let (limits, _handle) = Limits::start(
+            LimitOptions {
+                max_viewers: Some(10),
+                max_viewers_per_address: 1,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                rate_limit: None,
+            },
+            TrustedProxies::new(Vec::new()),
+        );
+
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let clients: Clients = Default::default();
+        clients.lock().unwrap().insert(
+            ViewerId::Session("0".repeat(32)),
+            Client {
+                ip,
+                connected: Instant::now(),
+                last_seen: Instant::now(),
+                bytes_sent: 0,
+                last_segment: None,
+            },
+        );
+        let slot = limits.viewer_slot(clients.clone());
+
+        let from =
+            |ip: &str| warp::test::request().remote_addr(format!("{}:1234", ip).parse().unwrap());
+        assert!(from("1.2.3.4").filter(&slot).await.is_err());
+        assert!(from("5.6.7.8").filter(&slot).await.is_ok());
+        assert_eq!(clients.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn last_slot() {
+        let (limits, _handle) = Limits::start(
+            LimitOptions {
+                max_viewers: Some(2),
+                max_viewers_per_address: 2,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                rate_limit: None,
+            },
+            TrustedProxies::new(Vec::new()),
+        );
+
+        let clients: Clients = Default::default();
+        let slot = limits.take_viewer_slot(clients.clone(), "test".to_string());
+
+        let from =
+            |ip: &str| warp::test::request().remote_addr(format!("{}:1234", ip).parse().unwrap());
+        let results = futures::future::join_all(
+            ["1.2.3.4", "5.6.7.8", "9.9.9.9"]
+                .iter()
+                .map(|ip| from(ip).filter(&slot)),
+        )
+        .await;
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+        assert_eq!(clients.lock().unwrap().len(), 2);
+        // whoever got in keeps their place
+        assert!(from("1.2.3.4").filter(&slot).await.is_ok());
+    }
+}
@@ -0,0 +1,257 @@
+use super::{status::Throughput, Clients};
+use crate::ffmpeg::EncoderState;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+use warp::{reply::Response, Filter, Rejection, Reply};
+
+// ffmpeg makes a segment every 2 seconds
+const SEGMENT_BUCKETS: [f64; 9] = [0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 4.0, 6.0, 10.0];
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str) -> fmt::Result {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count)?;
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count)?;
+        writeln!(out, "{}_sum {}", name, self.sum)?;
+        writeln!(out, "{}_count {}", name, self.count)?;
+        Ok(())
+    }
+}
+
+/// counters that only ever go up, kept for /metrics
+pub struct Metrics {
+    requests: Mutex<BTreeMap<u16, u64>>,
+    bytes_served: Mutex<BTreeMap<&'static str, u64>>,
+    segment_production: Mutex<Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: Default::default(),
+            bytes_served: Default::default(),
+            segment_production: Mutex::new(Histogram::new(&SEGMENT_BUCKETS)),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_request(&self, status: u16) {
+        *self.requests.lock().unwrap().entry(status).or_default() += 1;
+    }
+
+    pub fn record_bytes(&self, kind: &'static str, bytes: u64) {
+        *self.bytes_served.lock().unwrap().entry(kind).or_default() += bytes;
+    }
+
+    pub fn bytes_served(&self) -> BTreeMap<&'static str, u64> {
+        self.bytes_served.lock().unwrap().clone()
+    }
+
+    /// time between new media segments showing up in the manifest
+    pub fn record_segment_interval(&self, interval: Duration) {
+        self.segment_production
+            .lock()
+            .unwrap()
+            .observe(interval.as_secs_f64());
+    }
+
+    fn render(&self, clients: &Clients, throughput: &Throughput, encoder: &EncoderState) -> String {
+        let mut out = String::new();
+        // writing to a String can't fail
+        let _ignore = self.write(&mut out, clients, throughput, encoder);
+        out
+    }
+
+    fn write(
+        &self,
+        out: &mut String,
+        clients: &Clients,
+        throughput: &Throughput,
+        encoder: &EncoderState,
+    ) -> fmt::Result {
+        macro_rules! metric {
+            ($name:expr, $kind:expr, $help:expr) => {
+                writeln!(out, "# HELP {} {}", $name, $help)?;
+                writeln!(out, "# TYPE {} {}", $name, $kind)?;
+            };
+        }
+
+        metric!("dash_viewers", "gauge", "Connected viewers.");
+        writeln!(out, "dash_viewers {}", clients.lock().unwrap().len())?;
+
+        metric!(
+            "dash_bytes_served_total",
+            "counter",
+            "Bytes sent to viewers by file type."
+        );
+        for (kind, bytes) in self.bytes_served.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "dash_bytes_served_total{{type=\"{}\"}} {}",
+                kind, bytes
+            )?;
+        }
+
+        metric!(
+            "dash_bytes_per_second",
+            "gauge",
+            "Bytes sent to viewers during the last second."
+        );
+        writeln!(
+            out,
+            "dash_bytes_per_second {}",
+            throughput.bytes_per_second.load(Ordering::SeqCst)
+        )?;
+
+        metric!(
+            "dash_http_requests_total",
+            "counter",
+            "Http requests by response status."
+        );
+        for (status, count) in self.requests.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "dash_http_requests_total{{status=\"{}\"}} {}",
+                status, count
+            )?;
+        }
+
+        metric!(
+            "dash_segment_production_seconds",
+            "histogram",
+            "Time between new media segments appearing in the manifest."
+        );
+        self.segment_production
+            .lock()
+            .unwrap()
+            .write(out, "dash_segment_production_seconds")?;
+
+        metric!("ffmpeg_up", "gauge", "Whether the encoder is running.");
+        writeln!(out, "ffmpeg_up {}", encoder.uptime().is_some() as u8)?;
+
+        metric!(
+            "ffmpeg_restarts_total",
+            "counter",
+            "Times the encoder was started again."
+        );
+        writeln!(out, "ffmpeg_restarts_total {}", encoder.restarts())?;
+
+        if let Some(stats) = encoder.stats() {
+            metric!("ffmpeg_fps", "gauge", "Frames encoded per second.");
+            writeln!(out, "ffmpeg_fps {}", stats.fps)?;
+
+            if let Some(speed) = stats.speed {
+                metric!(
+                    "ffmpeg_speed",
+                    "gauge",
+                    "Encoding speed relative to realtime."
+                );
+                writeln!(out, "ffmpeg_speed {}", speed)?;
+            }
+
+            if let Some(bitrate_kbps) = stats.bitrate_kbps {
+                metric!("ffmpeg_bitrate_kbps", "gauge", "Output bitrate.");
+                writeln!(out, "ffmpeg_bitrate_kbps {}", bitrate_kbps)?;
+            }
+
+            metric!("ffmpeg_frames_total", "counter", "Frames encoded.");
+            writeln!(out, "ffmpeg_frames_total {}", stats.frame)?;
+
+            metric!(
+                "ffmpeg_dropped_frames_total",
+                "counter",
+                "Frames dropped by the encoder."
+            );
+            writeln!(out, "ffmpeg_dropped_frames_total {}", stats.dropped_frames)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// GET /metrics in prometheus text format
+pub fn route(
+    metrics: Arc<Metrics>,
+    clients: Clients,
+    throughput: Arc<Throughput>,
+    encoder: Arc<EncoderState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).map(move || {
+        warp::reply::with_header(
+            metrics.render(&clients, &throughput, &encoder),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+        .into_response()
+    })
+}
+
+/// label for bytes_served
+pub fn file_kind(path: &str) -> &'static str {
+    if path.ends_with(".mpd") {
+        "manifest"
+    } else if path.contains("init-") {
+        "init"
+    } else if path.ends_with(".webm") || path.ends_with(".m4s") || path.ends_with(".mp4") {
+        "segment"
+    } else {
+        "other"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let mut histogram = Histogram::new(&[1.0, 2.0, 5.0]);
+        for value in &[0.5, 1.0, 1.5, 3.0, 8.0] {
+            histogram.observe(*value);
+        }
+
+        let mut out = String::new();
+        histogram.write(&mut out, "test").unwrap();
+        assert_eq!(
+            out,
+            "test_bucket{le=\"1\"} 2\n\
+             test_bucket{le=\"2\"} 3\n\
+             test_bucket{le=\"5\"} 4\n\
+             test_bucket{le=\"+Inf\"} 5\n\
+             test_sum 14\n\
+             test_count 5\n"
+        );
+    }
+}
@@ -1,131 +1,315 @@
+mod access_log;
+mod admin;
+mod auth;
+mod bandwidth;
 #[cfg(feature = "tls")]
 mod cert;
+mod chat;
+mod client;
+mod delivery;
+mod events;
+mod limits;
+mod metrics;
+mod page;
+mod player;
+mod poster;
+mod status;
+mod stream;
+mod tracker;
 
-use crate::error::*;
-use futures::prelude::*;
+use self::{
+    access_log::{AccessLog, RequestInfo},
+    admin::{Admin, AdminUnauthorized},
+    auth::{Auth, Unauthorized},
+    client::{Connection, TrustedProxies, ViewerId},
+    limits::{Limited, Limits},
+    page::{Page, Template},
+    stream::{Shared, Stream},
+};
+pub use self::{
+    auth::AuthOptions,
+    chat::Chat,
+    client::Cidr,
+    limits::LimitOptions,
+    page::PageOptions,
+    stream::{Drained, StreamOptions},
+};
+use crate::{
+    error::*,
+    store::{SegmentStore, MANIFEST_NAME},
+};
+use bytes::Bytes;
 use log::*;
-use reqwest::header::CONTENT_LENGTH;
+use rand::RngCore;
+use reqwest::header::SET_COOKIE;
+use serde::Serialize;
 use std::{
     collections::HashMap,
     convert::Infallible,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Instant,
+};
+use warp::{
+    filters::BoxedFilter,
+    http::{HeaderValue, StatusCode},
+    hyper::{
+        self,
+        server::conn::AddrStream,
+        service::{make_service_fn, service_fn, Service},
     },
-    time::{Duration, Instant},
+    reply::Response,
+    Filter, Rejection, Reply,
 };
-use warp::{fs::File, http::StatusCode, Filter, Rejection, Reply};
-
-const INDEX: &str = include_str!("index.html");
-
-pub async fn start(
-    addr: SocketAddr,
-    temp_dir: PathBuf,
-    log: bool,
-    tls: bool,
-    maybe_cert_path: Option<PathBuf>,
-    maybe_key_path: Option<PathBuf>,
-) -> Result<()> {
-    let clients: Arc<Mutex<HashMap<IpAddr, Instant>>> = Default::default();
-    let sent_bytes = Arc::new(AtomicUsize::new(0));
-
-    let checker_handle = {
-        let clients = clients.clone();
-        let sent_bytes = sent_bytes.clone();
-
-        let (f, handle) = async move {
-            let mut max_bytes_per_second = 0;
-
-            loop {
-                tokio::time::sleep(Duration::from_secs(1)).await;
-                let now = Instant::now();
-                {
-                    let mut to_remove = Vec::new();
-                    let mut clients = clients.lock().unwrap();
-                    for (ip, time) in clients.iter() {
-                        if now - *time > Duration::from_secs(3 * 2) {
-                            to_remove.push(*ip);
-                        }
-                    }
 
-                    for ip in to_remove {
-                        clients.remove(&ip);
-                        info!("client {} left ({} clients)", ip, clients.len());
-                    }
+pub struct WebOptions {
+    pub addr: SocketAddr,
+    /// one unnamed stream, or any number of named ones
+    pub streams: Vec<StreamOptions>,
+    pub log: bool,
+    pub access_log_path: Option<PathBuf>,
+    /// where viewers reach us, for links in the log
+    pub public_url: Option<String>,
+    pub tls: bool,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub chat: Option<Chat>,
+    pub auth: AuthOptions,
+    pub trusted_proxies: Vec<Cidr>,
+    pub limits: LimitOptions,
+    /// enables /admin and lets /api/admin control the encoders
+    pub admin_password: Option<String>,
+    /// load the player from here instead of the bundled copy
+    pub player_cdn: Option<String>,
+    pub page: PageOptions,
+}
+
+pub struct Client {
+    pub ip: IpAddr,
+    pub connected: Instant,
+    pub last_seen: Instant,
+    pub bytes_sent: u64,
+    /// the newest segment number they fetched, to know when they've reached the end
+    pub last_segment: Option<u64>,
+}
+
+impl Client {
+    /// the entry for `id`, added if they're new
+    pub fn track<'a>(
+        clients: &'a mut HashMap<ViewerId, Client>,
+        id: ViewerId,
+        ip: IpAddr,
+        label: &str,
+    ) -> &'a mut Client {
+        let len = clients.len();
+        clients.entry(id.clone()).or_insert_with(|| {
+            info!("{}: client {} connected ({} clients)", label, id, len + 1);
+            Client {
+                ip,
+                connected: Instant::now(),
+                last_seen: Instant::now(),
+                bytes_sent: 0,
+                last_segment: None,
+            }
+        })
+    }
+}
+
+pub type Clients = Arc<Mutex<HashMap<ViewerId, Client>>>;
+
+/// GET /api/streams
+#[derive(Serialize)]
+struct StreamListing {
+    name: String,
+    url: String,
+    live: bool,
+    viewers: usize,
+}
+
+pub async fn start(options: WebOptions) -> Result<()> {
+    let WebOptions {
+        addr,
+        streams: stream_options,
+        log,
+        access_log_path,
+        public_url,
+        tls,
+        tls_cert_path: maybe_cert_path,
+        tls_key_path: maybe_key_path,
+        chat,
+        auth,
+        trusted_proxies,
+        limits,
+        admin_password,
+        player_cdn,
+        page,
+    } = options;
+
+    if player_cdn.is_none() && !player::is_bundled() {
+        warn!(
+            "built without the bundled player, viewers will load it from {}",
+            player::cdn_url()
+        );
+    }
+    let player_script = player::script_url(player_cdn.as_deref());
+
+    let proxies = TrustedProxies::new(trusted_proxies);
+
+    let protocol = if tls { "https" } else { "http" };
+
+    let links_base = match public_url {
+        Some(public_url) => public_url,
+        // nobody can open 0.0.0.0, so give them something to put after their own address
+        None if addr.ip().is_unspecified() => "/".to_string(),
+        None => format!("{}://{}/", protocol, addr),
+    };
+    let (limits, limits_handle) = Limits::start(limits, proxies.clone());
+
+    let (auth, auth_handle) = Auth::start(auth, links_base, limits.clone())?;
+
+    let (access_log, access_log_handle) = AccessLog::start(log, access_log_path.as_deref())?;
+
+    let shared = Shared {
+        page: &page,
+        player_script: &player_script,
+        chat: chat.as_ref(),
+        proxies: &proxies,
+        limits: &limits,
+        protocol,
+        log,
+    };
+
+    let mut streams = Vec::new();
+    let mut stream_handles = Vec::new();
+    let mut stream_routes: Option<BoxedFilter<(Response,)>> = None;
+    for options in stream_options {
+        let (stream, route, handle) = Stream::start(options, &shared)?;
+        streams.push(stream);
+        stream_handles.push(handle);
+        stream_routes = Some(match stream_routes {
+            Some(routes) => routes.or(route).unify().boxed(),
+            None => route,
+        });
+    }
+    let stream_routes = match stream_routes {
+        Some(routes) => routes,
+        None => bail!("no streams to serve"),
+    };
+    let streams = Arc::new(streams);
+    let manifest_paths: Vec<_> = streams
+        .iter()
+        .map(|stream| format!("{}{}", stream.path(), MANIFEST_NAME))
+        .collect();
+
+    // named streams live under /live/<name>/, with a list of them at /
+    let routes = if streams.iter().all(|stream| stream.name.is_some()) {
+        let listing = Page::new(page.clone(), player_script.clone(), false, None)?;
+
+        let index = warp::path::end()
+            .and(proxies.public_base(protocol))
+            .and_then(move |base_url: String| {
+                let listing = listing.clone();
+                async move {
+                    let html = listing.render(Template::Streams, &base_url).await;
+                    Ok::<_, Infallible>(warp::reply::html(html).into_response())
                 }
+            });
 
-                // show bitrate
-                {
-                    let byte_count = sent_bytes.swap(0, Ordering::SeqCst);
-                    if log {
-                        info!("{:.1} kbps", byte_count / 1000);
-                    }
+        let list = {
+            let streams = streams.clone();
+            warp::path!("api" / "streams")
+                .and(warp::get())
+                .map(move || {
+                    let list: Vec<_> = streams
+                        .iter()
+                        .map(|stream| StreamListing {
+                            name: stream.name.clone().unwrap_or_default(),
+                            url: stream.path()[1..].to_string(),
+                            live: stream.is_live(),
+                            viewers: stream.clients.lock().unwrap().len(),
+                        })
+                        .collect();
+                    warp::reply::with_header(warp::reply::json(&list), "cache-control", "no-cache")
+                        .into_response()
+                })
+        };
 
-                    if byte_count > max_bytes_per_second {
-                        max_bytes_per_second = byte_count;
-                        info!("new max: {} kbps", max_bytes_per_second / 1000);
+        index.or(list).unify().or(stream_routes).unify().boxed()
+    } else {
+        stream_routes
+    };
+
+    let login = auth.login_route();
+
+    // admins have their own password, so they don't need to get past viewer auth
+    let admin = Admin::new(admin_password, streams.clone(), limits.clone());
+
+    let routes = limits
+        .filter()
+        .and(
+            login.or(admin.routes()).unify().or(admin
+                .session()
+                .map(|| None)
+                .or(auth.filter())
+                .unify()
+                .and(routes)
+                .map(|set_cookie: Option<HeaderValue>, reply: _| {
+                    let mut response = Reply::into_response(reply);
+                    if let Some(set_cookie) = set_cookie {
+                        response.headers_mut().append(SET_COOKIE, set_cookie);
                     }
-                }
+                    response
+                })),
+        )
+        .recover(handle_rejection);
+
+    let routes = access_log::request_info(&proxies)
+        .and(routes)
+        .map(move |info: RequestInfo, reply: _| {
+            let response = Reply::into_response(reply);
+            let status = response.status().as_u16();
+
+            // counted towards the stream that was requested
+            let stream = streams
+                .iter()
+                .find(|stream| stream.serves(info.path()))
+                .cloned();
+            if let Some(stream) = &stream {
+                stream.metrics.record_request(status);
             }
-        }
-        .remote_handle();
-        tokio::spawn(f);
-        handle
-    };
 
-    let routes = warp::path::end()
-        .map(|| warp::reply::html(INDEX))
-        .or(warp::addr::remote()
-            .and(warp::header::optional::<IpAddr>("x-forwarded-for"))
-            .and(warp::fs::dir(temp_dir.to_owned()))
-            .map(
-                move |addr: Option<SocketAddr>, proxy_ip: Option<IpAddr>, file: File| {
-                    if let Some(ip) = proxy_ip.or_else(|| addr.map(|addr| addr.ip())) {
-                        let mut clients = clients.lock().unwrap();
-                        let len = clients.len();
-                        clients
-                            .entry(ip)
-                            .and_modify(|time| {
-                                *time = Instant::now();
-                            })
-                            .or_insert_with(|| {
-                                info!("client {} connected ({} clients)", ip, len + 1);
-                                Instant::now()
-                            });
+            let on_chunk = {
+                let kind = metrics::file_kind(info.path());
+                let stream = stream.clone();
+                move |bytes: u64| {
+                    if let Some(stream) = &stream {
+                        stream
+                            .sent_bytes
+                            .fetch_add(bytes as usize, Ordering::SeqCst);
+                        stream.metrics.record_bytes(kind, bytes);
                     }
+                }
+            };
 
-                    let response = file.into_response();
-                    if let Some(s) = response.headers().get(CONTENT_LENGTH) {
-                        if let Ok(s) = s.to_str() {
-                            if let Ok(byte_count) = s.parse::<usize>() {
-                                sent_bytes.fetch_add(byte_count, Ordering::SeqCst);
-                            }
+            let on_finish = {
+                let access_log = access_log.clone();
+                move |bytes: u64, complete: bool| {
+                    if let (Some(stream), Some(id)) = (&stream, info.viewer_id()) {
+                        if let Some(client) = stream.clients.lock().unwrap().get_mut(&id) {
+                            client.bytes_sent += bytes;
                         }
                     }
 
-                    response
-                },
-            ))
-        .recover(handle_rejection)
-        .with(warp::cors().allow_any_origin());
-    // TODO this code is causing "higher-ranked lifetime error" and i don't know why
-    // .with(warp::log::custom(move |info| {
-    //     if log {
-    //         debug!(
-    //             "{:?} \"{} {}\" {} \"{}\" {:?}",
-    //             info.remote_addr(),
-    //             info.method(),
-    //             info.path(),
-    //             info.status().as_u16(),
-    //             info.referer().unwrap_or("-"),
-    //             info.elapsed(),
-    //         );
-    //     }
-    // }));
+                    access_log.log(&info, status, bytes, complete);
+                }
+            };
 
-    let protocol = if tls { "https" } else { "http" };
+            bandwidth::count_body(response, on_chunk, on_finish)
+        })
+        .with(warp::cors().allow_any_origin())
+        .map(Reply::into_response)
+        .boxed();
 
     info!("starting {} server at {}://{}/", protocol, protocol, addr);
 
@@ -141,7 +325,9 @@ pub async fn start(
 
             match result.await {
                 Ok(ip) => {
-                    info!("external link {}://{}:{}/stream.mpd", protocol, ip, port);
+                    for path in manifest_paths {
+                        info!("external link {}://{}:{}{}", protocol, ip, port, path);
+                    }
                 }
                 Err(e) => {
                     warn!("error looking up external ip: {}", e);
@@ -150,10 +336,10 @@ pub async fn start(
         });
     }
 
-    let server = warp::serve(routes);
     if tls {
         #[cfg(feature = "tls")]
         {
+            let server = warp::serve(routes);
             let (cert, key) = cert::generate_cert_and_key()?;
 
             let cert_bytes = cert.to_pem()?;
@@ -176,25 +362,180 @@ pub async fn start(
             server.bind(addr).await;
         }
     } else {
-        server.bind(addr).await;
+        serve(routes, addr).await?;
     };
 
-    drop(checker_handle);
+    drop(stream_handles);
+    drop(auth_handle);
+    drop(limits_handle);
+    drop(access_log_handle);
 
     Ok(())
 }
 
-async fn handle_rejection(rejection: Rejection) -> std::result::Result<impl Reply, Infallible> {
+/// like warp::serve, but it also tells the routes which http version each request used
+async fn serve(routes: BoxedFilter<(Response,)>, addr: SocketAddr) -> Result<()> {
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |stream: &AddrStream| {
+        let remote = stream.remote_addr();
+        let mut service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(
+                move |mut request: hyper::Request<hyper::Body>| {
+                    let connection = Connection {
+                        remote,
+                        version: request.version(),
+                    };
+                    request.extensions_mut().insert(connection);
+                    service.call(request)
+                },
+            ))
+        }
+    });
+
+    hyper::Server::try_bind(&addr)
+        .chain_err(|| format!("couldn't listen on {}", addr))?
+        .serve(make_service)
+        .await
+        .chain_err(|| "http server")
+}
+
+/// internal server that ffmpeg uploads its dash output to, returns the base url to give it
+pub fn start_ingest(store: SegmentStore) -> Result<String> {
+    // other local users can reach the port too, so uploads need this in their path
+    let mut token = [0; 16];
+    rand::thread_rng().fill_bytes(&mut token);
+    let token = hex::encode(token);
+
+    // only ffmpeg should be able to reach this
+    let (addr, server) = warp::serve(ingest_routes(store, token.clone()))
+        .try_bind_ephemeral((Ipv4Addr::LOCALHOST, 0))?;
+    tokio::spawn(server);
+    debug!("ingest server at http://{}/", addr);
+
+    Ok(format!("http://{}/{}/", addr, token))
+}
+
+/// PUT/POST and DELETE /<token>/<name>
+fn ingest_routes(
+    store: SegmentStore,
+    token: String,
+) -> impl Filter<Extract = (StatusCode,), Error = Rejection> + Clone {
+    let token_path = warp::path(token);
+
+    let put = {
+        let store = store.clone();
+        token_path
+            .clone()
+            .and(warp::put().or(warp::post()).unify())
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(warp::body::bytes())
+            .map(move |name: String, data: Bytes| {
+                trace!("ingest {} ({} bytes)", name, data.len());
+                store.put(name, data);
+                StatusCode::CREATED
+            })
+    };
+
+    let delete = token_path
+        .and(warp::delete())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .map(move |name: String| {
+            trace!("ingest delete {}", name);
+            if store.remove(&name) {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::NOT_FOUND
+            }
+        });
+
+    put.or(delete).unify()
+}
+
+async fn handle_rejection(rejection: Rejection) -> std::result::Result<Response, Infallible> {
+    if let Some(unauthorized) = rejection.find::<Unauthorized>() {
+        return Ok(unauthorized.response());
+    }
+
+    if let Some(unauthorized) = rejection.find::<AdminUnauthorized>() {
+        return Ok(unauthorized.response());
+    }
+
+    if let Some(limited) = rejection.find::<Limited>() {
+        return Ok(limited.response());
+    }
+
     // hack so that cors works with 404 errors
     // this will create a successful reply which our with(cors) will then handle
     // https://github.com/seanmonstar/warp/issues/518
     if rejection.is_not_found() {
-        Ok(warp::reply::with_status("", StatusCode::NOT_FOUND))
+        Ok(warp::reply::with_status("", StatusCode::NOT_FOUND).into_response())
     } else {
         warn!("unhandled rejection: {:?}", rejection);
-        Ok(warp::reply::with_status(
-            "",
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ))
+        Ok(warp::reply::with_status("", StatusCode::INTERNAL_SERVER_ERROR).into_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+    #[tokio::test]
+    async fn ingest_needs_the_token() {
+        let store = SegmentStore::new();
+        let routes = ingest_routes(store.clone(), TOKEN.to_string());
+
+        for method in &["PUT", "POST"] {
+            let response = warp::test::request()
+                .method(method)
+                .path("/stream.mpd")
+                .body("evil")
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            let response = warp::test::request()
+                .method(method)
+                .path("/wrong/stream.mpd")
+                .body("evil")
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        assert!(store.get(MANIFEST_NAME).is_none());
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/stream.mpd")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn ingest_with_the_token() {
+        let store = SegmentStore::new();
+        let routes = ingest_routes(store.clone(), TOKEN.to_string());
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path(&format!("/{}/stream.mpd", TOKEN))
+            .body("manifest")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(store.get(MANIFEST_NAME).unwrap(), "manifest");
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path(&format!("/{}/stream.mpd", TOKEN))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(store.get(MANIFEST_NAME).is_none());
     }
 }
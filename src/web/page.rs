@@ -0,0 +1,339 @@
+use crate::{error::*, ffmpeg::EncoderState, store::MANIFEST_NAME};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{io, path::PathBuf, sync::Arc};
+use url::Url;
+use warp::{filters::BoxedFilter, http::StatusCode, path::Peek, reply::Response, Filter, Reply};
+
+const INDEX: &str = include_str!("index.html");
+const EMBED: &str = include_str!("embed.html");
+const STREAMS: &str = include_str!("streams.html");
+
+// until the encoder says otherwise
+const DEFAULT_VIDEO_SIZE: (u32, u32) = (1280, 720);
+
+#[derive(Clone, Copy)]
+pub enum Template {
+    Index,
+    /// the player alone, for iframes
+    Embed,
+    /// links to every stream, when there's more than one
+    Streams,
+}
+
+impl Template {
+    fn file_name(self) -> &'static str {
+        match self {
+            Template::Index => "index.html",
+            Template::Embed => "embed.html",
+            Template::Streams => "streams.html",
+        }
+    }
+
+    fn builtin(self) -> &'static str {
+        match self {
+            Template::Index => INDEX,
+            Template::Embed => EMBED,
+            Template::Streams => STREAMS,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OembedQuery {
+    url: Option<String>,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Oembed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: String,
+    provider_name: &'static str,
+    provider_url: String,
+    html: String,
+    width: u32,
+    height: u32,
+    thumbnail_url: String,
+    thumbnail_width: u32,
+    thumbnail_height: u32,
+}
+
+#[derive(Clone)]
+pub struct PageOptions {
+    pub title: String,
+    pub description: Option<String>,
+    /// image shown before the stream starts playing
+    pub poster: Option<String>,
+    pub logo: Option<String>,
+    pub background_color: String,
+    pub text_color: String,
+    pub accent_color: String,
+    /// seconds behind live that players aim for
+    pub latency_target: f64,
+    /// a custom index.html template and files that override the built-in ones
+    pub web_root: Option<PathBuf>,
+}
+
+/// everything a page template can use, also given to scripts as `{{config}}`
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PageConfig<'a> {
+    title: &'a str,
+    description: Option<&'a str>,
+    poster: Option<&'a str>,
+    logo: Option<&'a str>,
+    background_color: &'a str,
+    text_color: &'a str,
+    accent_color: &'a str,
+    latency_target: f64,
+    manifest_url: &'a str,
+    player_script: &'a str,
+    chat: bool,
+}
+
+/// the player page, rendered from the built-in or a custom template
+#[derive(Clone)]
+pub struct Page {
+    variables: Arc<Vec<(&'static str, String)>>,
+    title: Arc<str>,
+    poster: Option<Arc<str>>,
+    web_root: Option<PathBuf>,
+    /// none for the page listing streams
+    encoder: Option<Arc<EncoderState>>,
+}
+
+impl Page {
+    pub fn new(
+        options: PageOptions,
+        player_script: String,
+        chat: bool,
+        encoder: Option<Arc<EncoderState>>,
+    ) -> Result<Self> {
+        for color in [
+            &options.background_color,
+            &options.text_color,
+            &options.accent_color,
+        ] {
+            check_color(color)?;
+        }
+
+        if !(options.latency_target > 0.0 && options.latency_target.is_finite()) {
+            bail!("latency target has to be more than 0 seconds");
+        }
+
+        if let Some(web_root) = &options.web_root {
+            if !web_root.is_dir() {
+                bail!("web root {:?} isn't a directory", web_root);
+            }
+        }
+
+        let config = PageConfig {
+            title: &options.title,
+            description: options.description.as_deref(),
+            poster: options.poster.as_deref(),
+            logo: options.logo.as_deref(),
+            background_color: &options.background_color,
+            text_color: &options.text_color,
+            accent_color: &options.accent_color,
+            latency_target: options.latency_target,
+            manifest_url: MANIFEST_NAME,
+            player_script: &player_script,
+            chat,
+        };
+        // "<" only shows up inside strings, where the escape means the same thing
+        let config = serde_json::to_string(&config)
+            .chain_err(|| "serializing page config")?
+            .replace('<', "\\u003c");
+
+        let optional = |value: &Option<String>| escape_html(value.as_deref().unwrap_or_default());
+        let variables = vec![
+            ("title", escape_html(&options.title)),
+            ("description", optional(&options.description)),
+            ("poster", optional(&options.poster)),
+            ("logo", optional(&options.logo)),
+            ("background_color", options.background_color.clone()),
+            ("text_color", options.text_color.clone()),
+            ("accent_color", options.accent_color.clone()),
+            ("latency_target", options.latency_target.to_string()),
+            ("manifest_url", MANIFEST_NAME.to_string()),
+            ("player_script", escape_html(&player_script)),
+            ("config", config),
+        ];
+
+        Ok(Self {
+            variables: Arc::new(variables),
+            title: options.title.into(),
+            poster: options.poster.map(Into::into),
+            web_root: options.web_root,
+            encoder,
+        })
+    }
+
+    /// read again each time so a custom template can be edited while streaming,
+    /// `base_url` is where the viewer reached us, for links shared elsewhere
+    pub async fn render(&self, template: Template, base_url: &str) -> String {
+        let custom = match &self.web_root {
+            Some(web_root) => {
+                match tokio::fs::read_to_string(web_root.join(template.file_name())).await {
+                    Ok(template) => Some(template),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => {
+                        warn!("reading custom {}: {}", template.file_name(), e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let (width, height) = self.video_size();
+        let oembed_url = Url::parse_with_params(
+            &format!("{}oembed", base_url),
+            &[("url", base_url), ("format", "json")],
+        )
+        .map(String::from)
+        .unwrap_or_default();
+        let links = [
+            ("base_url", base_url.to_string()),
+            ("embed_url", format!("{}embed", base_url)),
+            ("oembed_url", oembed_url),
+            ("poster_url", self.poster_url(base_url)),
+            ("video_width", width.to_string()),
+            ("video_height", height.to_string()),
+        ];
+
+        let mut page = custom.unwrap_or_else(|| template.builtin().to_string());
+        for (name, value) in links
+            .iter()
+            .map(|(name, value)| (*name, escape_html(value)))
+        {
+            page = page.replace(&format!("{{{{{}}}}}", name), &value);
+        }
+        for (name, value) in self.variables.iter() {
+            page = page.replace(&format!("{{{{{}}}}}", name), value);
+        }
+        page
+    }
+
+    /// the encoder's output resolution
+    fn video_size(&self) -> (u32, u32) {
+        self.encoder
+            .as_ref()
+            .and_then(|encoder| encoder.info())
+            .and_then(|info| {
+                let (width, height) = info.video_resolution.split_once('x')?;
+                Some((width.parse().ok()?, height.parse().ok()?))
+            })
+            .unwrap_or(DEFAULT_VIDEO_SIZE)
+    }
+
+    /// the configured poster, or a frame from the stream
+    fn poster_url(&self, base_url: &str) -> String {
+        let poster = self.poster.as_deref().unwrap_or("poster.jpg");
+        Url::parse(base_url)
+            .and_then(|base| base.join(poster))
+            .map(String::from)
+            .unwrap_or_else(|_| poster.to_string())
+    }
+
+    /// https://oembed.com/ for the embed player
+    pub fn oembed(&self, base_url: &str, query: OembedQuery) -> Response {
+        if query.format.map(|format| format != "json").unwrap_or(false) {
+            return StatusCode::NOT_IMPLEMENTED.into_response();
+        }
+        // we can only describe ourselves
+        if let Some(url) = &query.url {
+            if !url.starts_with(base_url) {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+        }
+
+        let (video_width, video_height) = self.video_size();
+        let scale = [
+            query.maxwidth.map(|max| max as f64 / video_width as f64),
+            query.maxheight.map(|max| max as f64 / video_height as f64),
+        ]
+        .iter()
+        .flatten()
+        .fold(1.0_f64, |scale, &limit| scale.min(limit));
+        let width = (video_width as f64 * scale).round() as u32;
+        let height = (video_height as f64 * scale).round() as u32;
+
+        let embed_url = format!("{}embed", base_url);
+        let html = format!(
+            "<iframe src=\"{}\" width=\"{}\" height=\"{}\" frameborder=\"0\" \
+             allow=\"autoplay; fullscreen\" allowfullscreen></iframe>",
+            escape_html(&embed_url),
+            width,
+            height
+        );
+
+        warp::reply::json(&Oembed {
+            version: "1.0",
+            kind: "video",
+            title: self.title.to_string(),
+            provider_name: env!("CARGO_PKG_NAME"),
+            provider_url: base_url.to_string(),
+            html,
+            width,
+            height,
+            thumbnail_url: self.poster_url(base_url),
+            thumbnail_width: video_width,
+            thumbnail_height: video_height,
+        })
+        .into_response()
+    }
+
+    /// files from the web root, ahead of the built-in ones
+    pub fn assets(&self) -> BoxedFilter<(Response,)> {
+        match self.web_root.clone() {
+            Some(web_root) => warp::path::peek()
+                .and_then(|peek: Peek| async move {
+                    // served rendered instead
+                    let name = peek.as_str();
+                    let templates = [Template::Index, Template::Embed, Template::Streams];
+                    if templates
+                        .iter()
+                        .any(|template| template.file_name() == name)
+                    {
+                        Err(warp::reject::not_found())
+                    } else {
+                        Ok(())
+                    }
+                })
+                .untuple_one()
+                .and(warp::fs::dir(web_root))
+                .map(Reply::into_response)
+                .boxed(),
+
+            None => warp::any()
+                .and_then(|| async { Err::<Response, _>(warp::reject::not_found()) })
+                .boxed(),
+        }
+    }
+}
+
+/// css colors end up in a style tag, so only allow what colors are made of
+fn check_color(color: &str) -> Result<()> {
+    let valid = !color.is_empty()
+        && color
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "#(),.% ".contains(c));
+    if !valid {
+        bail!("{:?} isn't a css color", color);
+    }
+    Ok(())
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,93 @@
+use super::delivery::Encoding;
+use std::sync::OnceLock;
+use warp::{
+    filters::BoxedFilter,
+    http::{
+        header::{CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, VARY},
+        HeaderValue,
+    },
+    reply::Response,
+    Filter,
+};
+
+/// what `src/web/vendor/update.sh` downloads, and what we fall back to from the cdn
+pub const SHAKA_VERSION: &str = "4.12.0";
+
+pub const SCRIPT_NAME: &str = "shaka-player.compiled.js";
+
+// build.rs turns this on when the vendored player exists
+#[cfg(bundled_player)]
+const BUNDLED: Option<&str> = Some(include_str!("vendor/shaka-player.compiled.js"));
+#[cfg(not(bundled_player))]
+const BUNDLED: Option<&str> = None;
+
+pub fn is_bundled() -> bool {
+    BUNDLED.is_some()
+}
+
+pub fn cdn_url() -> String {
+    format!(
+        "https://cdn.jsdelivr.net/npm/shaka-player@{}/dist/{}",
+        SHAKA_VERSION, SCRIPT_NAME
+    )
+}
+
+/// where the page loads the player from
+pub fn script_url(cdn: Option<&str>) -> String {
+    match cdn {
+        Some(url) => url.to_string(),
+        None if is_bundled() => SCRIPT_NAME.to_string(),
+        None => cdn_url(),
+    }
+}
+
+/// the bundled player, compressed once on first request
+pub fn route() -> BoxedFilter<(Response,)> {
+    static BROTLI: OnceLock<Vec<u8>> = OnceLock::new();
+    static GZIP: OnceLock<Vec<u8>> = OnceLock::new();
+
+    warp::path(SCRIPT_NAME)
+        .and(warp::path::end())
+        .and(warp::get().or(warp::head()).unify())
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and_then(|accept_encoding: Option<String>| async move {
+            let script = BUNDLED.ok_or_else(warp::reject::not_found)?;
+
+            let encoding = Encoding::choose(accept_encoding.as_deref());
+            let body = match encoding {
+                Some(encoding) => {
+                    let cell = match encoding {
+                        Encoding::Brotli => &BROTLI,
+                        Encoding::Gzip => &GZIP,
+                    };
+                    cell.get_or_init(|| {
+                        encoding
+                            .compress(script.as_bytes())
+                            .expect("compressing into memory")
+                    })
+                    .clone()
+                    .into()
+                }
+                None => script.into(),
+            };
+
+            let mut response = Response::new(body);
+            let headers = response.headers_mut();
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/javascript"),
+            );
+            // not versioned in the url, so it has to be revalidated eventually
+            headers.insert(
+                CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=86400"),
+            );
+            headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+            if let Some(encoding) = encoding {
+                headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            }
+
+            Ok::<_, warp::Rejection>(response)
+        })
+        .boxed()
+}
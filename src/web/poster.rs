@@ -0,0 +1,146 @@
+use crate::{error::*, store::Segments};
+use bytes::{Bytes, BytesMut};
+use log::*;
+use std::{
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWriteExt, process::Command, sync::Mutex};
+use warp::{
+    filters::BoxedFilter,
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        HeaderValue,
+    },
+    reply::Response,
+    Filter,
+};
+
+// a frame this old is still a fine preview
+const POSTER_MAX_AGE: Duration = Duration::from_secs(30);
+// one frame shouldn't take anywhere near this long
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// a still from the live stream, for link previews
+pub fn route(segments: Segments) -> BoxedFilter<(Response,)> {
+    let cached: Arc<Mutex<Option<(Instant, Bytes)>>> = Default::default();
+
+    warp::path("poster.jpg")
+        .and(warp::path::end())
+        .and(warp::get().or(warp::head()).unify())
+        .and_then(move || {
+            let segments = segments.clone();
+            let cached = cached.clone();
+            async move {
+                // held while capturing, so a burst of previews only runs ffmpeg once
+                let mut cached = cached.lock().await;
+
+                let fresh = cached
+                    .as_ref()
+                    .filter(|(captured, _)| captured.elapsed() < POSTER_MAX_AGE)
+                    .map(|(_, jpeg)| jpeg.clone());
+
+                let jpeg = match fresh {
+                    Some(jpeg) => Some(jpeg),
+                    None => match capture(&segments).await {
+                        Ok(Some(jpeg)) => {
+                            *cached = Some((Instant::now(), jpeg.clone()));
+                            Some(jpeg)
+                        }
+                        Ok(None) => None,
+                        Err(e) => {
+                            warn!("capturing poster: {}", e);
+                            None
+                        }
+                    },
+                };
+
+                let jpeg = jpeg.ok_or_else(warp::reject::not_found)?;
+                let mut response = Response::new(jpeg.into());
+                let headers = response.headers_mut();
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+                headers.insert(
+                    CACHE_CONTROL,
+                    HeaderValue::from_static("public, max-age=30"),
+                );
+                Ok::<_, warp::Rejection>(response)
+            }
+        })
+        .boxed()
+}
+
+/// the first frame of the newest finished video segment
+async fn capture(segments: &Segments) -> Result<Option<Bytes>> {
+    let files = segments.list()?;
+
+    // the first representation is the video
+    let mut chunks: Vec<_> = files
+        .iter()
+        .filter(|(name, _)| name.starts_with("chunk-stream0-"))
+        .collect();
+    chunks.sort_by_key(|(_, version)| version.modified);
+
+    // the newest one might still be written
+    let chunk = match chunks.iter().rev().nth(1).or_else(|| chunks.last()) {
+        Some((name, _)) => name.as_str(),
+        None => return Ok(None),
+    };
+    let init = match init_name(chunk) {
+        Some(init) => init,
+        None => return Ok(None),
+    };
+
+    let (init_data, chunk_data) = match (segments.read(&init).await?, segments.read(chunk).await?) {
+        (Some(init_data), Some(chunk_data)) => (init_data, chunk_data),
+        _ => return Ok(None),
+    };
+
+    // an init segment followed by a media segment is a playable file
+    let mut input = BytesMut::with_capacity(init_data.len() + chunk_data.len());
+    input.extend_from_slice(&init_data);
+    input.extend_from_slice(&chunk_data);
+
+    let mut child = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0"])
+        .args(["-frames:v", "1", "-f", "image2", "-c:v", "mjpeg", "pipe:1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .chain_err(|| "starting ffmpeg")?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = tokio::spawn(async move {
+        // ffmpeg stops reading after one frame, so a broken pipe is expected
+        let _ignore = stdin.write_all(&input).await;
+    });
+
+    // dropping the child on timeout kills it
+    let output = match tokio::time::timeout(CAPTURE_TIMEOUT, child.wait_with_output()).await {
+        Ok(output) => output?,
+        Err(_elapsed) => {
+            writer.abort();
+            bail!("ffmpeg took too long to get a frame from {}", chunk);
+        }
+    };
+    let _ignore = writer.await;
+
+    if !output.status.success() || output.stdout.is_empty() {
+        bail!(
+            "ffmpeg couldn't get a frame from {}: {}",
+            chunk,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(Some(output.stdout.into()))
+}
+
+/// chunk-stream0-123-00042.webm is played after init-stream0-123.webm
+fn init_name(chunk: &str) -> Option<String> {
+    let (stem, extension) = chunk.strip_prefix("chunk-")?.rsplit_once('.')?;
+    let (representation, _number) = stem.rsplit_once('-')?;
+    Some(format!("init-{}.{}", representation, extension))
+}
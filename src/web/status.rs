@@ -0,0 +1,142 @@
+use super::{metrics::Metrics, tracker::SegmentTracker, Clients};
+use crate::ffmpeg::{EncoderInfo, EncoderState, EncoderStats};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use warp::{reply::Response, Filter, Rejection, Reply};
+
+#[derive(Default)]
+pub struct Throughput {
+    pub bytes_per_second: AtomicUsize,
+    pub max_bytes_per_second: AtomicUsize,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    encoder: EncoderStatus,
+    clients: Vec<ClientStatus>,
+    throughput: ThroughputStatus,
+    segments: SegmentsStatus,
+}
+
+#[derive(Serialize)]
+struct EncoderStatus {
+    running: bool,
+    uptime_secs: Option<f64>,
+    restarts: u64,
+    stats: Option<EncoderStats>,
+    #[serde(flatten)]
+    info: Option<EncoderInfo>,
+}
+
+#[derive(Serialize)]
+struct ClientStatus {
+    #[serde(flatten)]
+    identity: Option<ClientIdentity>,
+    connected_secs: f64,
+    last_seen_secs_ago: f64,
+    bytes_sent: u64,
+}
+
+/// who a client is, only shown to admins
+#[derive(Serialize)]
+struct ClientIdentity {
+    viewer: String,
+    ip: IpAddr,
+}
+
+#[derive(Serialize)]
+struct ThroughputStatus {
+    bytes_per_second: usize,
+    max_bytes_per_second: usize,
+    bytes_sent_total: u64,
+    bytes_sent_by_type: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Serialize)]
+struct SegmentsStatus {
+    manifest_age_secs: Option<f64>,
+    latest_segment: Option<String>,
+    latest_segment_age_secs: Option<f64>,
+}
+
+/// GET /api/status
+pub fn route(
+    clients: Clients,
+    throughput: Arc<Throughput>,
+    metrics: Arc<Metrics>,
+    tracker: SegmentTracker,
+    encoder: Arc<EncoderState>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path!("api" / "status").and(warp::get()).map(move || {
+        let status = snapshot(&clients, &throughput, &metrics, &tracker, &encoder, false);
+        warp::reply::json(&status).into_response()
+    })
+}
+
+/// also shown on the admin page, which gets to see who the clients are
+pub fn snapshot(
+    clients: &Clients,
+    throughput: &Throughput,
+    metrics: &Metrics,
+    tracker: &SegmentTracker,
+    encoder: &EncoderState,
+    identities: bool,
+) -> Status {
+    let uptime = encoder.uptime();
+    let freshness = tracker.freshness();
+
+    let clients = clients.lock().unwrap();
+    let mut sorted: Vec<_> = clients.iter().collect();
+    sorted.sort_by_key(|(_id, client)| client.ip);
+    let clients = sorted
+        .into_iter()
+        .map(|(id, client)| ClientStatus {
+            identity: if identities {
+                Some(ClientIdentity {
+                    viewer: id.to_string(),
+                    ip: client.ip,
+                })
+            } else {
+                None
+            },
+            connected_secs: client.connected.elapsed().as_secs_f64(),
+            last_seen_secs_ago: client.last_seen.elapsed().as_secs_f64(),
+            bytes_sent: client.bytes_sent,
+        })
+        .collect();
+
+    let bytes_sent_by_type = metrics.bytes_served();
+
+    Status {
+        encoder: EncoderStatus {
+            running: uptime.is_some(),
+            uptime_secs: uptime.as_ref().map(Duration::as_secs_f64),
+            restarts: encoder.restarts(),
+            stats: encoder.stats(),
+            info: encoder.info(),
+        },
+        clients,
+        throughput: ThroughputStatus {
+            bytes_per_second: throughput.bytes_per_second.load(Ordering::SeqCst),
+            max_bytes_per_second: throughput.max_bytes_per_second.load(Ordering::SeqCst),
+            bytes_sent_total: bytes_sent_by_type.values().sum(),
+            bytes_sent_by_type,
+        },
+        segments: SegmentsStatus {
+            manifest_age_secs: freshness.manifest_age.as_ref().map(Duration::as_secs_f64),
+            latest_segment: freshness.latest_segment,
+            latest_segment_age_secs: freshness
+                .latest_segment_age
+                .as_ref()
+                .map(Duration::as_secs_f64),
+        },
+    }
+}
@@ -0,0 +1,550 @@
+use super::{
+    chat::{self, Chat},
+    client::{self, TrustedProxies, ViewerId},
+    delivery,
+    events::{self, StreamEvents},
+    limits::{Limited, Limits},
+    metrics::{self, Metrics},
+    page::{OembedQuery, Page, PageOptions, Template},
+    player, poster,
+    status::{self, Status, Throughput},
+    tracker::{self, SegmentTracker},
+    Client, Clients,
+};
+use crate::{error::*, ffmpeg::EncoderState, helpers::*, store::Segments, supervisor::Control};
+use futures::{
+    future::{self, RemoteHandle},
+    FutureExt,
+};
+use log::*;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use warp::{
+    filters::BoxedFilter,
+    http::{header::SET_COOKIE, Uri},
+    path::FullPath,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+// kicked viewers can come back after this
+const KICK_DURATION: Duration = Duration::from_secs(10 * 60);
+
+// gives the tracker time to read the manifest ffmpeg wrote as it exited
+const END_SETTLE: Duration = Duration::from_secs(1);
+
+/// one encoder's output
+pub struct StreamOptions {
+    /// served at /live/<name>/, or at / when it's the only stream
+    pub name: Option<String>,
+    pub segments: Segments,
+    pub encoder: Arc<EncoderState>,
+    pub control: Control,
+    pub drained: Drained,
+}
+
+/// set once a stream has ended and its viewers have fetched the last segment or left
+#[derive(Clone)]
+pub struct Drained {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Drained {
+    fn default() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+}
+
+impl Drained {
+    fn set(&self) {
+        self.sender
+            .send_if_modified(|drained| !std::mem::replace(drained, true));
+    }
+
+    pub async fn wait(&self) {
+        let mut receiver = self.sender.subscribe();
+        let _ignore = receiver.wait_for(|drained| *drained).await;
+    }
+}
+
+/// what every stream's routes share
+pub struct Shared<'a> {
+    pub page: &'a PageOptions,
+    pub player_script: &'a str,
+    pub chat: Option<&'a Chat>,
+    pub proxies: &'a TrustedProxies,
+    pub limits: &'a Limits,
+    pub protocol: &'static str,
+    pub log: bool,
+}
+
+/// a stream's viewers and stats, for the server-wide layers
+#[derive(Clone)]
+pub struct Stream {
+    pub name: Option<String>,
+    pub clients: Clients,
+    pub sent_bytes: Arc<AtomicUsize>,
+    pub metrics: Arc<Metrics>,
+    pub encoder: Arc<EncoderState>,
+    pub control: Control,
+    throughput: Arc<Throughput>,
+    tracker: SegmentTracker,
+    kicked: Arc<Mutex<HashMap<ViewerId, Instant>>>,
+    drained: Drained,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "stream {}", name),
+            None => write!(f, "stream"),
+        }
+    }
+}
+
+impl Stream {
+    /// starts the stream's background tasks, which stop when the handle is dropped
+    pub fn start(
+        options: StreamOptions,
+        shared: &Shared,
+    ) -> Result<(Self, BoxedFilter<(Response,)>, RemoteHandle<()>)> {
+        let StreamOptions {
+            name,
+            segments,
+            encoder,
+            control,
+            drained,
+        } = options;
+
+        let clients: Clients = Default::default();
+        let sent_bytes = Arc::new(AtomicUsize::new(0));
+        let throughput: Arc<Throughput> = Default::default();
+        let metrics: Arc<Metrics> = Default::default();
+
+        let (tracker, tracker_handle) = SegmentTracker::start(segments.clone(), metrics.clone());
+
+        let stream = Self {
+            name,
+            clients,
+            sent_bytes,
+            metrics,
+            encoder,
+            control,
+            throughput: throughput.clone(),
+            tracker,
+            kicked: Default::default(),
+            drained,
+        };
+
+        let checker_handle = stream.start_checker(shared.log);
+
+        let page = {
+            let mut options = shared.page.clone();
+            if let Some(name) = &stream.name {
+                options.title = format!("{} - {}", name, options.title);
+            }
+            Page::new(
+                options,
+                shared.player_script.to_string(),
+                shared.chat.is_some(),
+                Some(stream.encoder.clone()),
+            )?
+        };
+
+        let status = status::route(
+            stream.clients.clone(),
+            throughput.clone(),
+            stream.metrics.clone(),
+            stream.tracker.clone(),
+            stream.encoder.clone(),
+        );
+
+        let metrics_route = metrics::route(
+            stream.metrics.clone(),
+            stream.clients.clone(),
+            throughput,
+            stream.encoder.clone(),
+        );
+
+        let (events, events_handle) = StreamEvents::start(
+            stream.clients.clone(),
+            stream.tracker.clone(),
+            stream.encoder.clone(),
+        );
+        let events_route = events::route(events);
+
+        let chat_route: BoxedFilter<(Response,)> = match shared.chat {
+            Some(chat) => {
+                let room = stream.name.clone().unwrap_or_else(|| "default".to_string());
+                chat::route(chat.clone(), room, shared.proxies.clone(), shared.limits).boxed()
+            }
+            None => warp::any()
+                .and_then(|| async { Err::<Response, _>(warp::reject::not_found()) })
+                .boxed(),
+        };
+
+        // don't serve segments until ffmpeg has finished writing them
+        let ready = {
+            let tracker = stream.tracker.clone();
+            warp::path::peek()
+                .and_then(move |peek: warp::path::Peek| {
+                    let tracker = tracker.clone();
+                    async move {
+                        if tracker.wait_until_ready(peek.as_str()).await {
+                            Ok(())
+                        } else {
+                            Err(warp::reject::not_found())
+                        }
+                    }
+                })
+                .untuple_one()
+        };
+
+        let files = delivery::route(segments.clone());
+
+        // clock source for the manifest's UTCTiming, so players don't need internet access
+        let time = warp::path("time").and(warp::path::end()).map(|| {
+            warp::reply::with_header(UtcTime::now().to_iso8601(), "cache-control", "no-cache")
+        });
+
+        let assets = page.assets();
+
+        // links to this stream, from where the viewer reached the server
+        let stream_base = {
+            let path = stream.path();
+            shared
+                .proxies
+                .public_base(shared.protocol)
+                .map(move |base_url: String| format!("{}{}", base_url, &path[1..]))
+        };
+
+        // the session cookie tells viewers behind the same address apart
+        let page_route = |path: BoxedFilter<()>, template: Template| {
+            let page = page.clone();
+            path.and(stream.admits(shared.proxies))
+                .and(shared.limits.viewer_slot(stream.clients.clone()))
+                .and(client::viewer_session())
+                .and(stream_base.clone())
+                .and_then(move |session: Option<String>, base_url: String| {
+                    let page = page.clone();
+                    async move {
+                        let html = page.render(template, &base_url).await;
+                        let mut response = warp::reply::html(html).into_response();
+                        if session.is_none() {
+                            response
+                                .headers_mut()
+                                .append(SET_COOKIE, client::new_session_cookie());
+                        }
+                        Ok::<_, Infallible>(response)
+                    }
+                })
+        };
+        let index = page_route(warp::path::end().boxed(), Template::Index);
+        let embed = page_route(
+            warp::path("embed").and(warp::path::end()).boxed(),
+            Template::Embed,
+        );
+
+        let oembed = {
+            let page = page.clone();
+            warp::path("oembed")
+                .and(warp::path::end())
+                .and(stream_base)
+                .and(warp::query::<OembedQuery>())
+                .map(move |base_url: String, query: OembedQuery| page.oembed(&base_url, query))
+        };
+
+        let tracked_files = {
+            let clients = stream.clients.clone();
+            let label = stream.to_string();
+            shared
+                .proxies
+                .client_ip()
+                .and(client::viewer_session())
+                .and(stream.admits(shared.proxies))
+                .and(
+                    shared
+                        .limits
+                        .take_viewer_slot(stream.clients.clone(), label.clone()),
+                )
+                .and(warp::path::peek())
+                .and(ready)
+                .and(files)
+                .map(
+                    move |client_ip: Option<IpAddr>,
+                          session: Option<String>,
+                          peek: warp::path::Peek,
+                          response: Response| {
+                        if let Some(ip) = client_ip {
+                            // players that don't keep cookies are counted by address
+                            let id = match session {
+                                Some(session) => ViewerId::Session(session),
+                                None => ViewerId::Ip(ip),
+                            };
+
+                            let segment = tracker::segment_number(peek.as_str());
+
+                            let mut clients = clients.lock().unwrap();
+                            let client = Client::track(&mut clients, id, ip, &label);
+                            client.ip = ip;
+                            client.last_seen = Instant::now();
+                            client.last_segment = client.last_segment.max(segment);
+                        }
+
+                        response
+                    },
+                )
+        };
+
+        let routes = index
+            .or(embed)
+            .or(oembed)
+            .or(poster::route(segments))
+            .or(assets)
+            .or(player::route())
+            .or(time)
+            .or(status)
+            .or(metrics_route)
+            .or(events_route)
+            .or(chat_route)
+            .or(tracked_files)
+            .map(Reply::into_response);
+
+        let routes = match &stream.name {
+            Some(name) => {
+                // relative links in the page need the trailing slash
+                let redirect = warp::path::end().and(warp::path::full()).and_then(
+                    |full: FullPath| async move {
+                        if full.as_str().ends_with('/') {
+                            Err(warp::reject::not_found())
+                        } else {
+                            let location = format!("{}/", full.as_str());
+                            match location.parse::<Uri>() {
+                                Ok(location) => {
+                                    Ok(warp::redirect::permanent(location).into_response())
+                                }
+                                Err(_) => Err(warp::reject::not_found()),
+                            }
+                        }
+                    },
+                );
+
+                warp::path("live")
+                    .and(warp::path(name.clone()))
+                    .and(redirect.or(routes).unify())
+                    .boxed()
+            }
+            None => routes.boxed(),
+        };
+
+        // dropping this one drops the others
+        let (f, handle) = future::join3(checker_handle, tracker_handle, events_handle)
+            .map(|_| ())
+            .remote_handle();
+        tokio::spawn(f);
+
+        Ok((stream, routes, handle))
+    }
+
+    /// where the stream's page is, like /live/name/
+    pub fn path(&self) -> String {
+        match &self.name {
+            Some(name) => format!("/live/{}/", name),
+            None => "/".to_string(),
+        }
+    }
+
+    /// whether a request path belongs to this stream
+    pub fn serves(&self, path: &str) -> bool {
+        match &self.name {
+            Some(_) => path.starts_with(&self.path()),
+            None => true,
+        }
+    }
+
+    /// for admins, with who each client is
+    pub fn status(&self) -> Status {
+        status::snapshot(
+            &self.clients,
+            &self.throughput,
+            &self.metrics,
+            &self.tracker,
+            &self.encoder,
+            true,
+        )
+    }
+
+    /// forgets a viewer and keeps them out for a while, by how /api/admin/status shows them
+    pub fn kick(&self, viewer: &str) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let id = match clients.keys().find(|id| id.to_string() == viewer) {
+            Some(id) => id.clone(),
+            None => return false,
+        };
+
+        let client = clients.remove(&id).expect("found above");
+        info!("{}: kicked client {} ({} clients)", self, id, clients.len());
+
+        // their address too, or clearing cookies would let them straight back in
+        let mut kicked = self.kicked.lock().unwrap();
+        kicked.insert(id, Instant::now());
+        kicked.insert(ViewerId::Ip(client.ip), Instant::now());
+        true
+    }
+
+    /// turns away kicked viewers, and new ones once the stream has ended
+    fn admits(
+        &self,
+        proxies: &TrustedProxies,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        let kicked = self.kicked.clone();
+        let clients = self.clients.clone();
+        let encoder = self.encoder.clone();
+
+        proxies
+            .client_ip()
+            .and(client::viewer_session())
+            .and_then(move |client_ip: Option<IpAddr>, session: Option<String>| {
+                let id = match (session, client_ip) {
+                    (Some(session), _) => Some(ViewerId::Session(session)),
+                    (None, Some(ip)) => Some(ViewerId::Ip(ip)),
+                    (None, None) => None,
+                };
+                let is_kicked = {
+                    let kicked = kicked.lock().unwrap();
+                    id.iter()
+                        .cloned()
+                        .chain(client_ip.map(ViewerId::Ip))
+                        .filter_map(|id| kicked.get(&id))
+                        .any(|kicked| kicked.elapsed() < KICK_DURATION)
+                };
+                let is_new = id
+                    .map(|id| !clients.lock().unwrap().contains_key(&id))
+                    .unwrap_or(true);
+
+                let result = if is_kicked {
+                    Err(warp::reject::custom(Limited::Denied))
+                } else if is_new && encoder.ended() {
+                    Err(warp::reject::custom(Limited::Ended))
+                } else {
+                    Ok(())
+                };
+                async move { result }
+            })
+            .untuple_one()
+    }
+
+    /// the manifest was updated recently
+    pub fn is_live(&self) -> bool {
+        !self.encoder.ended() && StreamEvents::is_online(&self.tracker)
+    }
+
+    /// every viewer has fetched the last segment, or left
+    fn viewers_done(&self) -> bool {
+        let last_segment = match self
+            .tracker
+            .freshness()
+            .latest_segment
+            .as_deref()
+            .and_then(tracker::segment_number)
+        {
+            Some(last_segment) => last_segment,
+            None => return true,
+        };
+
+        self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .all(|client| client.last_segment >= Some(last_segment))
+    }
+
+    /// forgets viewers that stopped fetching, tracks bitrate, and tells when an ended stream
+    /// has drained
+    fn start_checker(&self, log: bool) -> RemoteHandle<()> {
+        let stream = self.clone();
+
+        let (f, handle) = async move {
+            let mut ended: Option<Instant> = None;
+
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let now = Instant::now();
+
+                if stream.encoder.ended() {
+                    let ended = *ended.get_or_insert(now);
+                    if now - ended >= END_SETTLE && stream.viewers_done() {
+                        stream.drained.set();
+                    }
+                } else {
+                    ended = None;
+                }
+
+                {
+                    let mut to_remove = Vec::new();
+                    let mut clients = stream.clients.lock().unwrap();
+                    for (id, client) in clients.iter() {
+                        if now - client.last_seen > Duration::from_secs(3 * 2) {
+                            to_remove.push(id.clone());
+                        }
+                    }
+
+                    for id in to_remove {
+                        if let Some(client) = clients.remove(&id) {
+                            info!(
+                                "{}: client {} left ({} clients), sent {:.1} MB",
+                                stream,
+                                id,
+                                clients.len(),
+                                client.bytes_sent as f64 / 1_000_000.0
+                            );
+                        }
+                    }
+                }
+
+                stream
+                    .kicked
+                    .lock()
+                    .unwrap()
+                    .retain(|_, kicked| kicked.elapsed() < KICK_DURATION);
+
+                // show bitrate
+                {
+                    let throughput = &stream.throughput;
+                    let byte_count = stream.sent_bytes.swap(0, Ordering::SeqCst);
+                    throughput
+                        .bytes_per_second
+                        .store(byte_count, Ordering::SeqCst);
+                    let kbps = byte_count as f64 * 8.0 / 1000.0;
+                    if log {
+                        info!("{}: {:.1} kbps", stream, kbps);
+                    }
+
+                    let max_bytes_per_second = throughput
+                        .max_bytes_per_second
+                        .fetch_max(byte_count, Ordering::SeqCst);
+                    if byte_count > max_bytes_per_second {
+                        info!("{}: new max: {:.1} kbps", stream, kbps);
+                    }
+                }
+            }
+        }
+        .remote_handle();
+        tokio::spawn(f);
+
+        handle
+    }
+}
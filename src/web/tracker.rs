@@ -0,0 +1,201 @@
+use super::metrics::Metrics;
+use crate::store::{FileVersion, Segments, MANIFEST_NAME};
+use futures::{future::RemoteHandle, FutureExt};
+use log::*;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// a bit longer than a segment so a request for the next segment can wait for it
+const HOLD_TIMEOUT: Duration = Duration::from_secs(3);
+
+// how far past the newest segment a request is still worth holding
+const HOLD_AHEAD: u64 = 2;
+
+#[derive(Default)]
+struct Inner {
+    ready: HashSet<String>,
+    manifest_updated: Option<Instant>,
+    latest_segment: Option<(String, Instant)>,
+}
+
+pub struct Freshness {
+    pub manifest_age: Option<Duration>,
+    pub latest_segment: Option<String>,
+    pub latest_segment_age: Option<Duration>,
+}
+
+/// knows which files are completely written, by reading which ones the manifest refers to
+#[derive(Clone)]
+pub struct SegmentTracker {
+    inner: Arc<Mutex<Inner>>,
+    updates: watch::Receiver<()>,
+}
+
+impl SegmentTracker {
+    pub fn start(segments: Segments, metrics: Arc<Metrics>) -> (Self, RemoteHandle<()>) {
+        let inner: Arc<Mutex<Inner>> = Default::default();
+        let (sender, updates) = watch::channel(());
+
+        let (f, handle) = {
+            let inner = inner.clone();
+            async move {
+                let mut last_manifest_version: Option<FileVersion> = None;
+
+                loop {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+
+                    let files = match segments.list() {
+                        Ok(files) => files,
+                        Err(e) => {
+                            warn!("tracker: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let manifest_version = files.get(MANIFEST_NAME).copied();
+                    if manifest_version == last_manifest_version {
+                        continue;
+                    }
+                    last_manifest_version = manifest_version;
+
+                    let manifest = match segments.read(MANIFEST_NAME).await {
+                        Ok(Some(manifest)) => manifest,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("tracker: {}", e);
+                            continue;
+                        }
+                    };
+
+                    {
+                        let mut inner = inner.lock().unwrap();
+                        let now = Instant::now();
+                        let previous_segment_time =
+                            inner.latest_segment.as_ref().map(|(_, time)| *time);
+
+                        // forget files that ffmpeg has removed
+                        inner.ready.retain(|name| files.contains_key(name));
+
+                        for name in referenced_files(&String::from_utf8_lossy(&manifest)) {
+                            if inner.ready.insert(name.clone()) && !name.starts_with("init-") {
+                                inner.latest_segment = Some((name, now));
+                            }
+                        }
+
+                        if let (Some(previous), Some((_, latest))) =
+                            (previous_segment_time, &inner.latest_segment)
+                        {
+                            if *latest > previous {
+                                metrics.record_segment_interval(*latest - previous);
+                            }
+                        }
+
+                        inner.manifest_updated = Some(now);
+                    }
+
+                    sender.send_replace(());
+                }
+            }
+        }
+        .remote_handle();
+        tokio::spawn(f);
+
+        (Self { inner, updates }, handle)
+    }
+
+    fn is_ready(&self, name: &str) -> bool {
+        name == MANIFEST_NAME || self.inner.lock().unwrap().ready.contains(name)
+    }
+
+    pub fn freshness(&self) -> Freshness {
+        let inner = self.inner.lock().unwrap();
+        Freshness {
+            manifest_age: inner.manifest_updated.map(|time| time.elapsed()),
+            latest_segment: inner.latest_segment.as_ref().map(|(name, _)| name.clone()),
+            latest_segment_age: inner
+                .latest_segment
+                .as_ref()
+                .map(|(_, time)| time.elapsed()),
+        }
+    }
+
+    /// one of the next few segments of the encoder's current run
+    fn is_upcoming(&self, name: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let latest = match &inner.latest_segment {
+            Some((latest, _)) => latest,
+            None => return false,
+        };
+
+        match (segment_run(name), segment_run(latest)) {
+            (Some((run, number)), Some((latest_run, latest_number))) => {
+                run == latest_run && number > latest_number && number <= latest_number + HOLD_AHEAD
+            }
+            _ => false,
+        }
+    }
+
+    /// waits a little for the next segments, which aren't in the manifest yet
+    pub async fn wait_until_ready(&self, name: &str) -> bool {
+        if self.is_ready(name) {
+            return true;
+        }
+
+        // anything else would tie up the request for nothing
+        if !self.is_upcoming(name) {
+            return false;
+        }
+
+        let mut updates = self.updates.clone();
+        let wait = async {
+            while updates.changed().await.is_ok() {
+                if self.is_ready(name) {
+                    return true;
+                }
+            }
+            false
+        };
+
+        tokio::time::timeout(HOLD_TIMEOUT, wait)
+            .await
+            .unwrap_or(false)
+    }
+}
+
+/// 42 for chunk-stream0-<run>-00042.webm
+pub fn segment_number(name: &str) -> Option<u64> {
+    segment_run(name).map(|(_, number)| number)
+}
+
+/// the run and number of chunk-stream<representation>-<run>-<number>.<ext>
+fn segment_run(name: &str) -> Option<(&str, u64)> {
+    let stem = name.strip_prefix("chunk-stream")?;
+    let (stem, _extension) = stem.rsplit_once('.')?;
+    let (rest, number) = stem.rsplit_once('-')?;
+    let run = rest.split_once('-').map(|(_, run)| run).unwrap_or("");
+    Some((run, number.parse().ok()?))
+}
+
+/// init and media segment urls in a manifest
+fn referenced_files(manifest: &str) -> Vec<String> {
+    let mut names = Vec::new();
+
+    for attribute in ["media=\"", "sourceURL=\""] {
+        let mut rest = manifest;
+        while let Some(start) = rest.find(attribute) {
+            rest = &rest[start + attribute.len()..];
+            if let Some(end) = rest.find('"') {
+                names.push(rest[..end].to_string());
+                rest = &rest[end..];
+            }
+        }
+    }
+
+    names
+}